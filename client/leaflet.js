@@ -53,45 +53,61 @@ function make_map() {
         attribution: "© OpenStreetMap",
     }).addTo(map);
 
-    fetch("https://jotihunt.lucasholten.com/deelnemers.geojson")
-        .then((res) => res.json())
-        .then((data) => {
-            L.geoJSON(data, {
-                pointToLayer: function (feature, latlng) {
-                    L.circle(latlng, { radius: 500, opacity: 0.1 }).addTo(map);
-                    const color = fox_color(feature.properties.area);
-                    const icon = make_icon(false, color);
-                    return L.marker(latlng)
-                        .setIcon(icon)
-                        .bindTooltip(feature.properties.name)
-                        .bindPopup([latlng.lat, latlng.lng].toString());
-                },
-            }).addTo(map);
-        });
+    // the areas, shaded by their status
+    let areas = L.geoJSON(null, {
+        style: function (feature) {
+            return {
+                color: feature.properties.colour,
+                weight: 2,
+                fillOpacity: 0.15,
+            };
+        },
+        onEachFeature: function (feature, layer) {
+            layer.bindTooltip(feature.properties.name);
+        },
+    }).addTo(map);
+    function load_areas() {
+        fetch("https://jotihunt.lucasholten.com/areas.geojson")
+            .then((res) => res.json())
+            .then((data) => {
+                areas.clearLayers();
+                areas.addData(data);
+            });
+    }
+    load_areas();
+    // the status changes during the hunt
+    setInterval(load_areas, 60 * 1000);
 
     return map;
 }
 var map = make_map();
 
-let orga = add_marker(5.8725166117126575, 51.95402844147237, "orga", false);
+let orga = add_marker(51.95402844147237, 5.8725166117126575, "orga");
 set_custom(orga, "/stikkerbuilding.png");
 
-proj4.defs(
-    "EPSG:7415",
-    "+proj=sterea +lat_0=52.1561605555556 +lon_0=5.38763888888889 +k=0.9999079 +x_0=155000 +y_0=463000 +ellps=bessel +units=m +vunits=m +no_defs +type=crs",
-);
-export function add_marker(lat, lng, name, convert) {
-    let coord = [lat, lng];
-    if (convert) {
-        coord = proj4("EPSG:7415", "EPSG:4326", coord);
-    }
-    let marker = L.marker([coord[1], coord[0]])
+export function add_marker(lat, lng, name) {
+    let marker = L.marker([lat, lng])
         .bindTooltip(name)
-        .bindPopup([coord[1], coord[0]].toString())
+        .bindPopup([lat, lng].toString())
         .addTo(map);
     return marker;
 }
 
+// a participating group, the details are shown as text so they can not inject html
+export function add_participant(lat, lng, name, area, details) {
+    let popup = document.createElement("div");
+    for (const line of details.split("\n")) {
+        let div = document.createElement("div");
+        div.textContent = line;
+        popup.appendChild(div);
+    }
+    let icon = make_icon(false, fox_color(area));
+    return L.layerGroup([
+        L.circle([lat, lng], { radius: 500, opacity: 0.1 }),
+        L.marker([lat, lng]).setIcon(icon).bindTooltip(name).bindPopup(popup),
+    ]).addTo(map);
+}
+
 export function remove_layer(marker) {
     map.removeLayer(marker);
 }
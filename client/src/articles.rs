@@ -94,8 +94,8 @@ pub fn articles(key: &'static str) {
 
     sycamore::render_to(
         |cx| {
-            let (articles, _) = live_updated::<ArticleKey, SavedArticle>(cx, key, "articles");
-            let (status, _) = live_updated::<StatusKey, String>(cx, key, "status");
+            let articles = live_updated::<ArticleKey, SavedArticle>(cx, key, "articles").data;
+            let status = live_updated::<StatusKey, String>(cx, key, "status").data;
 
             let status_check = create_signal(cx, false);
             let everything = create_signal(cx, false);
@@ -1,19 +1,28 @@
-use std::collections::BTreeMap;
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    time::Duration,
+};
 
 use crate::leaflet::Marker;
 use crate::{HOSTNAME, WS_PROTOCOL};
 use futures::{
     self,
-    channel::mpsc::{self, UnboundedSender},
-    future, StreamExt, TryStreamExt,
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future, pin_mut, SinkExt, StreamExt, TryStreamExt,
 };
 use gloo::{
+    console::console_dbg,
     dialogs::alert,
     net::websocket::{futures::WebSocket, Message},
+    storage::{LocalStorage, SessionStorage, Storage},
+    timers::future::sleep,
+};
+use jotihunt_shared::coordinate::Coordinate;
+use jotihunt_shared::{
+    protocol::{ClientMessage, EditResult, Revision, ServerMessage, PROTOCOL_VERSION},
+    AtomicEdit,
 };
-use jotihunt_shared::domain::Fox;
-use jotihunt_shared::{AtomicEdit, Broadcast};
-use js_sys::Date;
 use serde::de::DeserializeOwned;
 use sycamore::{
     futures::spawn_local_scoped,
@@ -21,106 +30,404 @@ use sycamore::{
     reactive::Signal,
 };
 
-async fn write_data(
-    queue_read: mpsc::UnboundedReceiver<AtomicEdit>,
-    write: futures::stream::SplitSink<WebSocket, Message>,
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A connection that stays up this long starts the backoff over.
+const STABLE_UPTIME: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// Waiting before trying to connect again
+    Offline {
+        retry_in: Duration,
+    },
+    /// The server was updated, the page needs to be refreshed
+    Incompatible,
+}
+
+impl ConnectionState {
+    pub fn describe(&self) -> String {
+        match self {
+            ConnectionState::Connecting => "verbinden...".to_owned(),
+            ConnectionState::Connected => "verbonden".to_owned(),
+            ConnectionState::Offline { retry_in } => {
+                format!("offline, opnieuw over {}s", retry_in.as_secs())
+            }
+            ConnectionState::Incompatible => "ververs de pagina".to_owned(),
+        }
+    }
+}
+
+/// State that is kept between connections.
+struct SyncState<'cx> {
+    /// Edits that are not yet acknowledged by the server, stored in local storage
+    pending: RefCell<BTreeMap<u32, AtomicEdit>>,
+    storage_key: String,
+    next_id: Cell<u32>,
+    /// The last revision that was received
+    revision: Cell<Option<Revision>>,
+    connection: &'cx Signal<ConnectionState>,
+    queued: &'cx Signal<usize>,
+}
+
+/// Identifies the tab, so tabs do not overwrite each other's queue. It survives reloading.
+fn tab_id() -> String {
+    const KEY: &str = "jotihunt-tab";
+    SessionStorage::get(KEY).unwrap_or_else(|_| {
+        let id = format!("{:08x}", (js_sys::Math::random() * u32::MAX as f64) as u32);
+        let _ = SessionStorage::set(KEY, &id);
+        id
+    })
+}
+
+impl<'cx> SyncState<'cx> {
+    fn load(cx: BoundedScope<'cx, 'cx>, name: &str) -> Self {
+        // edits queued by an older version can not be read by the server anymore
+        let storage_key = format!("jotihunt-queue-{PROTOCOL_VERSION}-{name}-{}", tab_id());
+        let pending: BTreeMap<u32, AtomicEdit> =
+            LocalStorage::get(&storage_key).unwrap_or_default();
+        let next_id = pending.keys().last().copied().unwrap_or(0);
+        let queued = pending.len();
+        Self {
+            pending: RefCell::new(pending),
+            storage_key,
+            next_id: Cell::new(next_id),
+            revision: Cell::new(None),
+            connection: create_signal(cx, ConnectionState::Connecting),
+            queued: create_signal(cx, queued),
+        }
+    }
+
+    fn save(&self) {
+        let pending = self.pending.borrow();
+        self.queued.set(pending.len());
+        if let Err(err) = LocalStorage::set(&self.storage_key, &*pending) {
+            console_dbg!("could not save edits", err.to_string());
+        }
+    }
+
+    fn push(&self, edit: AtomicEdit) -> u32 {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        self.pending.borrow_mut().insert(id, edit);
+        self.save();
+        id
+    }
+
+    fn remove(&self, id: u32) -> Option<AtomicEdit> {
+        let edit = self.pending.borrow_mut().remove(&id);
+        self.save();
+        edit
+    }
+}
+
+fn encode(msg: &ClientMessage) -> Message {
+    Message::Bytes(postcard::to_stdvec(msg).unwrap())
+}
+
+/// Stores new edits, so they are not lost when offline, and passes their id to the writer.
+async fn queue_edits(
+    queue_read: UnboundedReceiver<AtomicEdit>,
+    ids: UnboundedSender<u32>,
+    state: &SyncState<'_>,
 ) {
-    let _ = queue_read
-        .map(|edit| {
-            let msg = Message::Bytes(postcard::to_stdvec(&edit).unwrap());
-            Ok(msg)
+    queue_read
+        .for_each(|edit| {
+            let _ = ids.unbounded_send(state.push(edit));
+            future::ready(())
         })
+        .await
+}
+
+async fn write_data(
+    ids: &mut UnboundedReceiver<u32>,
+    control_read: UnboundedReceiver<ClientMessage>,
+    state: &SyncState<'_>,
+    mut write: futures::stream::SplitSink<WebSocket, Message>,
+) {
+    let mut handshake = vec![
+        ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+        },
+        ClientMessage::Sync {
+            since: state.revision.get(),
+        },
+    ];
+    // edits that were made while offline, or that were not acknowledged
+    let replayed = state.next_id.get();
+    for (&id, edit) in state.pending.borrow().iter() {
+        handshake.push(ClientMessage::Edit {
+            id,
+            edit: edit.clone(),
+        });
+    }
+    for msg in handshake {
+        if write.send(encode(&msg)).await.is_err() {
+            return;
+        }
+    }
+
+    let edits = ids.filter_map(|id| {
+        let edit = state.pending.borrow().get(&id).cloned();
+        let msg = edit
+            .filter(|_| id > replayed)
+            .map(|edit| ClientMessage::Edit { id, edit });
+        future::ready(msg)
+    });
+    let _ = futures::stream::select(edits, control_read)
+        .map(|msg| Ok(encode(&msg)))
         .forward(write)
         .await;
 }
 
+/// An edit that was not applied because the value on the server was different.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Rejection {
+    pub edit: AtomicEdit,
+    /// The value on the server, empty if there is none
+    pub current: Vec<u8>,
+}
+
+impl Rejection {
+    /// Makes the same change again, but now based on the current value.
+    pub fn retry(&self) -> AtomicEdit {
+        AtomicEdit {
+            key: self.edit.key.clone(),
+            old: self.current.clone(),
+            new: self.edit.new.clone(),
+        }
+    }
+}
+
+fn decode_entry<K, V>(key: &[u8], value: &[u8]) -> Option<(K, V)>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    Some((
+        postcard::from_bytes(key).ok()?,
+        postcard::from_bytes(value).ok()?,
+    ))
+}
+
+fn set_revision(state: &SyncState<'_>, revision: u64) {
+    if let Some(mut current) = state.revision.get() {
+        current.revision = revision;
+        state.revision.set(Some(current));
+    }
+}
+
+/// Why reading from the server stopped.
+enum Closed {
+    /// The connection was lost, we can reconnect
+    Lost,
+    /// The server does not understand us, reconnecting will not help
+    Incompatible,
+}
+
+/// Handles a single message, returns an error if the connection should be closed.
+fn handle_message<K, V>(
+    msg: ServerMessage,
+    data: &Signal<BTreeMap<K, V>>,
+    rejections: &Signal<Vec<Rejection>>,
+    state: &SyncState<'_>,
+    control: &UnboundedSender<ClientMessage>,
+) -> Result<(), Closed>
+where
+    K: DeserializeOwned + Clone + Ord,
+    V: DeserializeOwned + Clone,
+{
+    match msg {
+        ServerMessage::Hello { version } => {
+            if version != PROTOCOL_VERSION {
+                return Err(Closed::Incompatible);
+            }
+            state.connection.set(ConnectionState::Connected);
+        }
+        ServerMessage::Snapshot { revision, entries } => {
+            let map = entries
+                .iter()
+                .filter_map(|(key, value)| decode_entry(key, value))
+                .collect();
+            data.set(map);
+            state.revision.set(Some(revision));
+        }
+        ServerMessage::Insert {
+            revision,
+            key,
+            value,
+        } => {
+            if let Some((key, value)) = decode_entry(&key, &value) {
+                data.modify().insert(key, value);
+            }
+            set_revision(state, revision);
+        }
+        ServerMessage::Remove { revision, key } => {
+            if let Ok(key) = postcard::from_bytes::<K>(&key) {
+                data.modify().remove(&key);
+            }
+            set_revision(state, revision);
+        }
+        ServerMessage::Ack { id, result } => {
+            let edit = state.remove(id);
+            match (edit, result) {
+                (Some(edit), EditResult::Rejected { current }) => {
+                    // a replayed edit that was already applied before the connection was lost
+                    if current != edit.new {
+                        rejections.modify().push(Rejection { edit, current })
+                    }
+                }
+                (Some(_), EditResult::Forbidden) => {
+                    alert("Een wijziging is niet opgeslagen, je mag alleen kijken.");
+                }
+                _ => {}
+            }
+        }
+        ServerMessage::Error { message } => {
+            // errors before the hello are about the handshake
+            if *state.connection.get() != ConnectionState::Connected {
+                return Err(Closed::Incompatible);
+            }
+            alert(&message);
+        }
+        ServerMessage::Ping => {
+            let _ = control.unbounded_send(ClientMessage::Pong);
+        }
+        ServerMessage::Pong => {}
+    }
+    Ok(())
+}
+
 async fn read_data<K, V>(
     read: futures::stream::SplitStream<WebSocket>,
-    data: &Signal<std::collections::BTreeMap<K, V>>,
-) where
+    data: &Signal<BTreeMap<K, V>>,
+    rejections: &Signal<Vec<Rejection>>,
+    state: &SyncState<'_>,
+    control: UnboundedSender<ClientMessage>,
+) -> Closed
+where
     K: DeserializeOwned + Clone + Ord,
     V: DeserializeOwned + Clone,
 {
-    let _ = read
-        .try_for_each(|msg| match msg {
-            Message::Text(_) => panic!("we want bytes"),
-            Message::Bytes(bin) => {
-                let broadcast: Broadcast = postcard::from_bytes(&bin).unwrap();
-                let key: K = postcard::from_bytes(&broadcast.key).unwrap();
-
-                if broadcast.value.is_empty() {
-                    data.modify().remove(&key);
-                } else {
-                    let fox: V = postcard::from_bytes(&broadcast.value).unwrap();
-                    data.modify().insert(key.clone(), fox);
+    let res = read
+        .map_err(|_| Closed::Lost)
+        .try_for_each(|msg| {
+            let bin = match msg {
+                Message::Bytes(bin) => bin,
+                Message::Text(text) => {
+                    console_dbg!("unexpected text message", text);
+                    return future::ok(());
                 }
-                future::ok(())
-            }
+            };
+            let msg = match postcard::from_bytes(&bin) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    console_dbg!("could not read message", err.to_string());
+                    return future::ok(());
+                }
+            };
+            future::ready(handle_message(msg, data, rejections, state, &control))
         })
         .await;
+    res.err().unwrap_or(Closed::Lost)
+}
 
-    let local_time = Date::new_0().to_time_string();
-    let msg = format!(
-        "verbinding verbroken: ververs de pagina voor nieuwe data!
-        {local_time}"
-    );
-    alert(&msg)
+/// Connects to the server, and reconnects with exponential backoff when the connection is lost.
+async fn keep_synced<K, V>(
+    ws_address: String,
+    mut ids: UnboundedReceiver<u32>,
+    data: &Signal<BTreeMap<K, V>>,
+    rejections: &Signal<Vec<Rejection>>,
+    state: &SyncState<'_>,
+) where
+    K: DeserializeOwned + Clone + Ord,
+    V: DeserializeOwned + Clone,
+{
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        state.connection.set(ConnectionState::Connecting);
+        let opened = js_sys::Date::now();
+        if let Ok(ws) = WebSocket::open(&ws_address) {
+            let (write, read) = ws.split();
+            let (control_write, control_read) = mpsc::unbounded();
+
+            let write = write_data(&mut ids, control_read, state, write);
+            let read = read_data(read, data, rejections, state, control_write);
+            pin_mut!(write, read);
+            // the writer stops when the connection is lost, the reader tells us why
+            let closed = match future::select(read, write).await {
+                future::Either::Left((closed, _)) => closed,
+                future::Either::Right(_) => Closed::Lost,
+            };
+            if let Closed::Incompatible = closed {
+                state.connection.set(ConnectionState::Incompatible);
+                return;
+            }
+        }
+
+        // start over with a short wait if we were connected for a while, a server that accepts
+        // and then drops every connection still gets the full backoff
+        let uptime = Duration::from_millis((js_sys::Date::now() - opened) as u64);
+        if *state.connection.get() == ConnectionState::Connected && uptime >= STABLE_UPTIME {
+            backoff = MIN_BACKOFF;
+        }
+        console_dbg!("verbinding verbroken", &ws_address);
+        state
+            .connection
+            .set(ConnectionState::Offline { retry_in: backoff });
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
 }
 
+/// A map that is kept in sync with a tree on the server.
+pub struct LiveData<'cx, K, V> {
+    pub data: &'cx ReadSignal<BTreeMap<K, V>>,
+    /// Edits sent here are stored until the server has received them
+    pub queue: &'cx UnboundedSender<AtomicEdit>,
+    /// Edits that were rejected by the server, until they are removed from it
+    pub rejections: &'cx Signal<Vec<Rejection>>,
+    pub connection: &'cx ReadSignal<ConnectionState>,
+    /// Number of edits that are not yet acknowledged by the server
+    pub queued: &'cx ReadSignal<usize>,
+}
+
+/// Keeps a map in sync with a tree on the server.
 pub fn live_updated<'cx, K, V>(
     cx: BoundedScope<'cx, 'cx>,
     key: &str,
     name: &str,
-) -> (
-    &'cx ReadSignal<BTreeMap<K, V>>,
-    &'cx UnboundedSender<AtomicEdit>,
-)
+) -> LiveData<'cx, K, V>
 where
     K: DeserializeOwned + Clone + Ord,
     V: DeserializeOwned + Clone,
 {
     let data = create_signal(cx, BTreeMap::<K, V>::new());
+    let rejections = create_signal(cx, Vec::new());
+    let state = create_ref(cx, SyncState::load(cx, name));
 
     let ws_address = format!("{WS_PROTOCOL}://{HOSTNAME}/{key}/{name}");
-    let ws = WebSocket::open(&ws_address).unwrap();
-
-    let (write, read) = ws.split();
     let (queue_write, queue_read) = mpsc::unbounded();
+    let (ids_write, ids_read) = mpsc::unbounded();
 
-    spawn_local_scoped(cx, write_data(queue_read, write));
-    spawn_local_scoped(cx, read_data(read, data));
-    (data, create_ref(cx, queue_write))
-}
-
-// creates a marker if both coordinates are valid
-// first tries converting fom RD, then accepts lat long
-pub fn make_marker(fox: &Fox, name: &str) -> Option<Marker> {
-    fn try_rd(fox: &Fox, name: &str) -> Option<Marker> {
-        Some(Marker::new(
-            make_value(&fox.latitude)?,
-            make_value(&fox.longitude)?,
-            name.to_owned(),
-            true,
-        ))
+    spawn_local_scoped(cx, queue_edits(queue_read, ids_write, state));
+    spawn_local_scoped(
+        cx,
+        keep_synced(ws_address, ids_read, data, rejections, state),
+    );
+    LiveData {
+        data,
+        queue: create_ref(cx, queue_write),
+        rejections,
+        connection: state.connection,
+        queued: state.queued,
     }
-    try_rd(fox, name).or_else(|| {
-        Some(Marker::new(
-            fox.longitude.parse().ok()?,
-            fox.latitude.parse().ok()?,
-            name.to_owned(),
-            false,
-        ))
-    })
 }
 
-// checks if the given value is a valid 4 digit number
-fn make_value(input: &str) -> Option<f64> {
-    // log!("{}", input);
-    let Ok(val) = input.parse::<u32>() else {
-        return None;
-    };
-    if input.len() != 4 {
-        return None;
-    };
-    Some((val * 100) as f64)
+// creates a marker if the coordinate is valid
+pub fn make_marker(coordinate: &Coordinate, name: &str) -> Option<Marker> {
+    let (lat, lon) = coordinate.to_wgs84()?;
+    Some(Marker::new(lat, lon, name.to_owned()))
 }
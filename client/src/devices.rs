@@ -0,0 +1,161 @@
+use futures::SinkExt;
+use gloo::{dialogs::alert, net::http::Request};
+use jotihunt_shared::{AtomicEdit, Device, Traccar, MARKER_COLOURS};
+use serde::{Deserialize, Serialize};
+use sycamore::{futures::spawn_local_scoped, prelude::*};
+
+use crate::{comms::LiveData, HOSTNAME, HTTP_PROTOCOL};
+
+#[derive(Serialize)]
+struct NewDevice {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceToken {
+    token: String,
+}
+
+/// Devices that reported without a token, with a description of their last report.
+async fn fetch_pending(key: &str) -> Vec<(String, String)> {
+    let url = format!("{HTTP_PROTOCOL}://{HOSTNAME}/{key}/admin/pending");
+    let Ok(res) = Request::get(&url).send().await else {
+        return vec![];
+    };
+    let pending: Vec<Traccar> = res.json().await.unwrap_or_default();
+    pending
+        .into_iter()
+        .map(|traccar| {
+            let description = format!("{} ({})", traccar.id, traccar.position);
+            (traccar.id, description)
+        })
+        .collect()
+}
+
+/// Approves the device and returns the url it should report to.
+async fn approve(key: &str, id: String) -> Option<String> {
+    let url = format!("{HTTP_PROTOCOL}://{HOSTNAME}/{key}/admin/devices");
+    let res = Request::post(&url)
+        .json(&NewDevice { id })
+        .ok()?
+        .send()
+        .await
+        .ok()?;
+    let device: DeviceToken = res.json().await.ok()?;
+    Some(format!(
+        "{HTTP_PROTOCOL}://{HOSTNAME}/traccar/{}",
+        device.token
+    ))
+}
+
+/// Lets admins approve new devices and choose how devices are shown on the map.
+pub fn device_admin<'cx, G: Html>(
+    cx: Scope<'cx>,
+    key: &'static str,
+    registry: &'cx LiveData<'cx, String, Device>,
+) -> View<G> {
+    let (data, queue_write) = (registry.data, registry.queue);
+
+    let devices = create_memo(cx, || {
+        data.get()
+            .iter()
+            .map(|(id, device)| (id.clone(), device.clone()))
+            .collect::<Vec<_>>()
+    });
+    let colours = create_signal(cx, MARKER_COLOURS.to_vec());
+
+    let pending = create_signal(cx, Vec::new());
+    let refresh = create_ref(cx, move || {
+        spawn_local_scoped(cx, async move { pending.set(fetch_pending(key).await) })
+    });
+    refresh();
+
+    view! {cx,
+        details {
+            summary {"Apparaten"}
+            Keyed(
+                iterable=devices,
+                view=move |cx, (id, device)| {
+                    let id = create_ref(cx, id);
+                    let old = create_ref(cx, device.clone());
+                    let name = create_signal(cx, device.name);
+                    let team = create_signal(cx, device.team);
+                    let colour = create_signal(cx, device.colour);
+                    let send_update = create_ref(cx, move |new: Option<Device>| {
+                        let edit = AtomicEdit{
+                            key: postcard::to_stdvec(id).unwrap(),
+                            old: postcard::to_stdvec(old).unwrap(),
+                            new: new.map(|new| postcard::to_stdvec(&new).unwrap()).unwrap_or_default(),
+                        };
+                        spawn_local_scoped(cx, async {queue_write.clone().send(edit).await.unwrap();});
+                    });
+                    let save = create_ref(cx, move || {
+                        send_update(Some(Device {
+                            name: name.get().as_ref().trim().to_string(),
+                            team: team.get().as_ref().trim().to_string(),
+                            colour: colour.get().as_ref().clone(),
+                        }))
+                    });
+                    view!{cx,
+                        div(class="field") {
+                            span {(id.clone())}
+                            input(size=8, bind:value=name, placeholder="naam", on:change=move |_| save())
+                            input(size=6, bind:value=team, placeholder="auto", on:change=move |_| save())
+                            select(bind:value=colour, on:change=move |_| save()) {
+                                option(value="", selected=colour.get().is_empty()) {"mens"}
+                                Keyed(
+                                    iterable=colours,
+                                    view=move |cx, c| {
+                                        let selected = c == colour.get().as_str();
+                                        view!{cx, option(value=c, selected=selected){(c)}}
+                                    },
+                                    key=|c| *c,
+                                )
+                            }
+                            input(type="button", value="Verwijderen", on:click=move |_| send_update(None))
+                        }
+                    }
+                },
+                key=|(id, device)| (id.clone(), device.clone()),
+            )
+            hr()
+            div(class="field") {
+                span {"Wachten op goedkeuring:"}
+                input(type="button", value="Vernieuwen", on:click=move |_| refresh())
+            }
+            Keyed(
+                iterable=pending,
+                view=move |cx, (id, description)| {
+                    let id = create_ref(cx, id);
+                    view!{cx,
+                        div(class="field") {
+                            p {(description)}
+                            input(type="button", value="Goedkeuren", on:click=move |_| {
+                                spawn_local_scoped(cx, async move {
+                                    let Some(url) = approve(key, id.clone()).await else {
+                                        alert("Goedkeuren is mislukt");
+                                        return;
+                                    };
+                                    if !data.get().contains_key(id) {
+                                        let edit = AtomicEdit{
+                                            key: postcard::to_stdvec(id).unwrap(),
+                                            old: vec![],
+                                            new: postcard::to_stdvec(&Device {
+                                                name: id.clone(),
+                                                ..Device::default()
+                                            }).unwrap(),
+                                        };
+                                        queue_write.clone().send(edit).await.unwrap();
+                                    }
+                                    alert(&format!("Deze url invullen in de traccar client van {id}: {url}"));
+                                    refresh();
+                                })
+                            })
+                        }
+                    }
+                },
+                key=|(id, _)| id.clone(),
+            )
+        }
+    }
+}
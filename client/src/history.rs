@@ -0,0 +1,70 @@
+use gloo::utils::document;
+use jotihunt_shared::{
+    domain::{Fox, FoxKey},
+    AuditEntry, AuditKey,
+};
+use sycamore::prelude::*;
+use wasm_bindgen::JsValue;
+
+use crate::comms::live_updated;
+
+fn show_fox(bin: &[u8]) -> String {
+    if bin.is_empty() {
+        return "-".to_owned();
+    }
+    match postcard::from_bytes::<Fox>(bin) {
+        Ok(fox) => fox.coordinate.to_string(),
+        Err(_) => "?".to_owned(),
+    }
+}
+
+fn describe(time: u64, entry: &AuditEntry) -> String {
+    let date = js_sys::Date::new(&JsValue::from_f64(time as f64));
+    let hours = date.get_hours();
+    let mins = date.get_minutes();
+
+    let fox = match postcard::from_bytes::<FoxKey>(&entry.key) {
+        Ok(key) => format!("{} {}", key.fox_name, key.time),
+        Err(_) => "?".to_owned(),
+    };
+    let old = show_fox(&entry.old);
+    let new = show_fox(&entry.new);
+    let failed = if entry.applied { "" } else { " (mislukt)" };
+    format!(
+        "{hours:0>2}:{mins:0>2} {}: {fox} ({old}) -> ({new}){failed}",
+        entry.user
+    )
+}
+
+pub fn history(key: &'static str) {
+    let history = document()
+        .get_element_by_id("history")
+        .expect("there is a history element");
+
+    sycamore::render_to(
+        |cx| {
+            let entries = live_updated::<AuditKey, AuditEntry>(cx, key, "audit").data;
+
+            let lines = create_memo(cx, || {
+                entries
+                    .get()
+                    .iter()
+                    .rev()
+                    .map(|(k, v)| (k.clone(), describe(k.time, v), v.applied))
+                    .collect::<Vec<_>>()
+            });
+
+            view! {cx,
+                summary {"Geschiedenis"}
+                Keyed(
+                    iterable=lines,
+                    view=|cx, (_, line, applied)| {
+                        view! {cx, p(applied=applied) {(line)}}
+                    },
+                    key=|(k, _, _)| k.clone()
+                )
+            }
+        },
+        &history,
+    );
+}
@@ -4,7 +4,8 @@ use wasm_bindgen::prelude::*;
 extern "C" {
     type JsMarker;
 
-    fn add_marker(lat: f64, lng: f64, name: String, convert: bool) -> JsMarker;
+    fn add_marker(lat: f64, lng: f64, name: String) -> JsMarker;
+    fn add_participant(lat: f64, lng: f64, name: &str, area: &str, details: &str) -> JsMarker;
     #[wasm_bindgen(js_name = remove_layer)]
     fn remove_marker(marker: &JsMarker);
     fn set_marker_color(marker: &JsMarker, color: &str);
@@ -24,8 +25,12 @@ extern "C" {
 pub struct Marker(JsMarker);
 
 impl Marker {
-    pub fn new(lat: f64, lng: f64, name: String, convert: bool) -> Self {
-        Self(add_marker(lat, lng, name, convert))
+    pub fn new(lat: f64, lng: f64, name: String) -> Self {
+        Self(add_marker(lat, lng, name))
+    }
+    /// A participating group with a circle around it, `details` has a line per field.
+    pub fn participant(lat: f64, lng: f64, name: &str, area: &str, details: &str) -> Self {
+        Self(add_participant(lat, lng, name, area, details))
     }
     pub fn set_color(&self, color: &str) {
         set_marker_color(&self.0, color)
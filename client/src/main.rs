@@ -1,10 +1,11 @@
-use std::{collections::BTreeMap, rc::Rc, time::Duration};
+use std::{cell::Cell, collections::BTreeMap, rc::Rc, time::Duration};
 
-use comms::live_updated;
+use comms::{live_updated, Rejection};
 use futures::SinkExt;
 use gloo::{dialogs::alert, net::http::Request, timers::future::sleep, utils::document};
 use jotihunt_shared::{
-    domain::{Fox, FoxKey},
+    coordinate::{Coordinate, CoordinateError},
+    domain::{AreaCheck, Fox, FoxKey},
     AtomicEdit,
 };
 use js_sys::Date;
@@ -17,21 +18,48 @@ use sycamore::{
 
 mod articles;
 mod comms;
+mod devices;
+mod history;
 mod leaflet;
 mod options;
+mod participants;
 
 const HOSTNAME: &str = "jotihunt.lucasholten.com";
 const WS_PROTOCOL: &str = "wss";
 const HTTP_PROTOCOL: &str = "https";
 
-fn location_editor(key: &'static str, fox_names: &[String]) {
+fn location_editor(key: &'static str) {
     let coord_editor = document()
         .get_element_by_id("coord_editor")
         .expect("there is a add_point button");
 
     sycamore::render_to(
         |cx| {
-            let (data, queue_write) = live_updated::<FoxKey, Fox>(cx, key, "locations");
+            let locations = live_updated::<FoxKey, Fox>(cx, key, "locations");
+            let (data, queue_write, rejections) =
+                (locations.data, locations.queue, locations.rejections);
+
+            let connection = create_memo(cx, move || {
+                let queued = *locations.queued.get();
+                let state = locations.connection.get().describe();
+                match queued {
+                    0 => state,
+                    _ => format!("{state}, {queued} wijziging(en) niet verstuurd"),
+                }
+            });
+
+            let checks = live_updated::<FoxKey, AreaCheck>(cx, key, "checks").data;
+            let fox_list = live_updated::<String, String>(cx, key, "fox_list").data;
+            let fox_names = create_memo(cx, || fox_list.get().keys().cloned().collect::<Vec<_>>());
+
+            let rejection_count = create_ref(cx, Cell::new(0));
+            create_effect(cx, || {
+                let count = rejections.get().len();
+                if count > rejection_count.get() {
+                    alert("Een wijziging is niet opgeslagen, iemand anders was je voor!");
+                }
+                rejection_count.set(count);
+            });
 
             let current_time = create_signal(cx, String::new());
 
@@ -74,7 +102,7 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                     let mut markers = vec![];
                     for (time, fox) in points {
                         let name = format!("{} ({})", fox_name, time);
-                        if let Some(marker) = comms::make_marker(&fox, &name) {
+                        if let Some(marker) = comms::make_marker(&fox.coordinate, &name) {
                             marker.set_fox(true);
                             line.push(&marker);
                             markers.push(marker);
@@ -88,7 +116,19 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                 create_memo(cx, || lines.get());
             }
 
-            let new_fox = create_signal(cx, fox_names[0].clone());
+            let new_fox = create_signal(cx, String::new());
+            let area = create_signal(cx, String::new());
+            // select the first fox once the list arrives
+            create_effect(cx, || {
+                let Some(first) = fox_names.get().first().cloned() else {
+                    return;
+                };
+                for selected in [new_fox, area] {
+                    if selected.get_untracked().is_empty() {
+                        selected.set(first.clone());
+                    }
+                }
+            });
 
             let slice_names = create_memo(cx, || {
                 let mut names: Vec<_> = data
@@ -102,31 +142,18 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                 names
             });
 
-            let area = create_signal(cx, fox_names[0].clone());
-
-            let fox_options = move || {
-                View::new_fragment(
-                    fox_names
-                        .iter()
-                        .map(|name| {
-                            let name = create_ref(cx, name.clone());
-                            view! {cx, option(value=*name){(*name)}}
-                        })
-                        .collect::<Vec<_>>(),
-                )
-            };
-            let fox_options_clone = fox_options();
-            let fox_options = fox_options();
-
             let hunt_coord = create_signal(cx, String::new());
 
+            let rejected = create_memo(cx, || rejections.get().as_ref().clone());
+
             view! {cx,
                 summary {"Coordinaten"}
+                div(class="connection") {(connection.get().to_string())}
                 div(class="field") {
                     input(bind:value=hunt_coord, placeholder="xxxx, yyyy of 51.xxx, 4.yyy")
                 }
                 div(class="field") {
-                    select(bind:value=area) {(fox_options)}
+                    select(bind:value=area) {(fox_options(cx, fox_names, area))}
                     input(type="time", bind:value=current_time)
                     input(type="button", value="Toevoegen", on:click=move|_| {
                         let hunt_coord = hunt_coord.get();
@@ -138,10 +165,21 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                             alert("geen vos geselecteerd");
                             return
                         }
-                        let Some((lat, long)) = hunt_coord.split_once(',') else {
+                        let Some((x, y)) = hunt_coord.split_once(',') else {
                             alert("coordinaat heeft geen comma");
                             return
                         };
+                        let coordinate = match Coordinate::parse(x, y) {
+                            Ok(coordinate) => coordinate,
+                            Err(CoordinateError::OutOfRange) => {
+                                alert("coordinaat ligt niet in Nederland");
+                                return
+                            }
+                            Err(_) => {
+                                alert("coordinaat niet herkend");
+                                return
+                            }
+                        };
                         let edit = AtomicEdit{
                             key: postcard::to_stdvec(&FoxKey {
                                 day: current_day.get().as_ref().clone(),
@@ -149,15 +187,34 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                                 fox_name: area.get().as_ref().clone(),
                             }).unwrap(),
                             old: Vec::new(),
-                            new: postcard::to_stdvec(&Fox{
-                                latitude: lat.trim().to_string(),
-                                longitude: long.trim().to_string()
-                            }).unwrap(),
+                            new: postcard::to_stdvec(&Fox{coordinate}).unwrap(),
                         };
                         spawn_local_scoped(cx, async {queue_write.clone().send(edit).await.unwrap();});
                     })
                 }
 
+                Keyed(
+                    iterable=rejected,
+                    view=move |cx, rejection| {
+                        let rejection = create_ref(cx, rejection);
+                        let remove = move || {
+                            rejections.modify().retain(|r| r != rejection);
+                        };
+                        view! {cx,
+                            div(class="field") {
+                                p {(describe_rejection(rejection))}
+                                input(type="button", value="Opnieuw", on:click=move |_| {
+                                    let edit = rejection.retry();
+                                    remove();
+                                    spawn_local_scoped(cx, async {queue_write.clone().send(edit).await.unwrap();});
+                                })
+                                input(type="button", value="Negeren", on:click=move |_| remove())
+                            }
+                        }
+                    },
+                    key=|rejection| rejection.clone(),
+                )
+
                 details {
                     summary {"Bewerken"}
                     div(class="field") {
@@ -178,31 +235,27 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                     Keyed(
                         iterable=old_values,
                         view=move|cx, (key, fox)| {
-                            let (key2, fox2) = (key.clone(), fox.clone());
-                            let fox2 = create_ref(cx, fox2);
-                            let latitude = create_signal(cx, fox.latitude);
-                            let longitude = create_signal(cx, fox.longitude);
+                            let key2 = key.clone();
+                            let key3 = create_ref(cx, key.clone());
+                            let warning = create_memo(cx, move || describe_check(checks.get().get(key3)));
+                            let fox2 = create_ref(cx, fox);
+                            let (old_x, old_y) = fox2.coordinate.fields();
+                            let (old_x, old_y) = (create_ref(cx, old_x), create_ref(cx, old_y));
+                            let x = create_signal(cx, old_x.clone());
+                            let y = create_signal(cx, old_y.clone());
+                            let coordinate = create_ref(cx, move || Coordinate::from_input(&x.get(), &y.get()));
                             let send_update = create_ref(cx, move || {
                                 let edit = AtomicEdit{
                                     key: postcard::to_stdvec(&key2).unwrap(),
                                     old: postcard::to_stdvec(fox2).unwrap(),
-                                    new: postcard::to_stdvec(&Fox{
-                                        latitude: latitude.get().as_ref().trim().to_string(),
-                                        longitude: longitude.get().as_ref().trim().to_string()
-                                    }).unwrap(),
+                                    new: postcard::to_stdvec(&Fox{coordinate: coordinate()}).unwrap(),
                                 };
                                 spawn_local_scoped(cx, async {queue_write.clone().send(edit).await.unwrap();});
                             });
                             view!{cx,
                                 div(class="field") {
-                                    input(type="button", value=(key.fox_name.clone()), on:click=move |_|{
-                                        if let Some(marker) = comms::make_marker(
-                                            &Fox {
-                                                latitude: latitude.get().as_ref().trim().to_string(),
-                                                longitude: longitude.get().as_ref().trim().to_string(),
-                                            },
-                                            "zoom",
-                                        ) {
+                                    input(type="button", value=(key.fox_name.clone()), suspicious=warning.get().is_some(), on:click=move |_|{
+                                        if let Some(marker) = comms::make_marker(&coordinate(), "zoom") {
                                             marker.set_color("grey");
                                             marker.zoom_to();
                                             spawn_local_scoped(cx, async {
@@ -211,16 +264,23 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                                             });
                                         }
                                     })
-                                    input(size=7, bind:value=latitude, placeholder="xxxx", updated={
-                                        latitude.get().as_ref()==&fox2.latitude
+                                    input(size=7, bind:value=x, placeholder="xxxx", updated={
+                                        x.get().as_ref()==old_x
                                     }, on:change=move |_|{
                                         send_update();
                                     })
-                                    input(size=7, bind:value=longitude, placeholder="yyyy", updated={
-                                        longitude.get().as_ref()==&fox2.longitude
+                                    input(size=7, bind:value=y, placeholder="yyyy", updated={
+                                        y.get().as_ref()==old_y
                                     }, on:change=move |_|{
                                         send_update();
                                     })
+                                    (match warning.get().as_ref() {
+                                        Some(warning) => {
+                                            let warning = warning.clone();
+                                            view!{cx, p(class="warning") {(warning)}}
+                                        }
+                                        None => View::empty(),
+                                    })
                                 }
                             }
                         },
@@ -228,7 +288,7 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                     )
                     hr()
                     div(class="field"){
-                        select(bind:value=new_fox) {(fox_options_clone)}
+                        select(bind:value=new_fox) {(fox_options(cx, fox_names, new_fox))}
                         input(type="button", value="Verwijderen", on:click=move |_|{
                             if current_time.get().is_empty() {
                                 alert("Selecteer eerst een tijdstip");
@@ -241,7 +301,7 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
                                 return
                             };
 
-                            if !old_value.latitude.is_empty() || !old_value.longitude.is_empty() {
+                            if !old_value.coordinate.is_empty() {
                                 alert("Alleen lege coordinaten kunnen verwijderd worden");
                                 return;
                             }
@@ -266,6 +326,46 @@ fn location_editor(key: &'static str, fox_names: &[String]) {
     );
 }
 
+fn fox_options<'cx, G: Html>(
+    cx: Scope<'cx>,
+    fox_names: &'cx ReadSignal<Vec<String>>,
+    selected: &'cx Signal<String>,
+) -> View<G> {
+    view! {cx,
+        Keyed(
+            iterable=fox_names,
+            view=move |cx, name| {
+                let is_selected = name == *selected.get();
+                view! {cx, option(value=name.clone(), selected=is_selected){(name)}}
+            },
+            key=|name| name.clone(),
+        )
+    }
+}
+
+/// A warning for locations that the server found outside their own area.
+fn describe_check(check: Option<&AreaCheck>) -> Option<String> {
+    match check? {
+        AreaCheck::Inside => None,
+        AreaCheck::Outside { area: Some(area) } => Some(format!("Ligt in deelgebied {area}")),
+        AreaCheck::Outside { area: None } => Some("Ligt buiten alle deelgebieden".to_owned()),
+    }
+}
+
+fn describe_rejection(rejection: &Rejection) -> String {
+    let Ok(key) = postcard::from_bytes::<FoxKey>(&rejection.edit.key) else {
+        return "Wijziging niet opgeslagen".to_owned();
+    };
+    let current = match postcard::from_bytes::<Fox>(&rejection.current) {
+        Ok(fox) => fox.coordinate.to_string(),
+        Err(_) => "leeg".to_owned(),
+    };
+    format!(
+        "{} {} niet opgeslagen, is nu: {current}",
+        key.fox_name, key.time
+    )
+}
+
 fn main() {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
 
@@ -278,18 +378,10 @@ fn main() {
         let key = res.unwrap().text().await.unwrap();
         let key = Box::leak(key.into_boxed_str());
 
-        let fox_names: Vec<String> =
-            gloo::net::http::Request::get(&format!("{HTTP_PROTOCOL}://{HOSTNAME}/fox_list.json"))
-                .send()
-                .await
-                .unwrap()
-                .json()
-                .await
-                .unwrap();
-
-        location_editor(key, &fox_names);
+        location_editor(key);
         option_panel(key);
         articles::articles(key);
+        history::history(key);
     });
 }
 
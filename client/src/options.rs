@@ -1,18 +1,42 @@
-use std::{collections::HashMap, future::ready};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::ready,
+};
 
 use futures::{channel::oneshot, FutureExt, StreamExt, TryStreamExt};
 use gloo::{
     console::console_dbg,
     dialogs::alert,
-    net::websocket::{futures::WebSocket, Message},
+    net::{
+        http::Request,
+        websocket::{futures::WebSocket, Message},
+    },
     timers::future::TimeoutFuture,
     utils::document,
 };
-use jotihunt_shared::{domain::Fox, Traccar};
+use jotihunt_shared::{Device, Role, Traccar};
 use mk_geolocation::{future::PositionStream, PositionOptions};
+use serde::Deserialize;
 use sycamore::{futures::spawn_local_scoped, prelude::*};
 
-use crate::{leaflet::Marker, HOSTNAME, WS_PROTOCOL};
+use crate::{
+    comms::{live_updated, make_marker},
+    devices::device_admin,
+    leaflet::Marker,
+    participants::participant_markers,
+    HOSTNAME, HTTP_PROTOCOL, WS_PROTOCOL,
+};
+
+#[derive(Deserialize)]
+struct Account {
+    role: Role,
+}
+
+async fn fetch_role(key: &str) -> Option<Role> {
+    let url = format!("{HTTP_PROTOCOL}://{HOSTNAME}/{key}/session");
+    let account: Account = Request::get(&url).send().await.ok()?.json().await.ok()?;
+    Some(account.role)
+}
 
 pub fn option_panel(key: &'static str) {
     let panel = document()
@@ -22,17 +46,31 @@ pub fn option_panel(key: &'static str) {
     sycamore::render_to(
         |cx| {
             let show_live = create_signal(cx, true);
+            let registry = create_ref(cx, live_updated::<String, Device>(cx, key, "registry"));
 
             create_effect_scoped(cx, move |cx| {
                 if *show_live.get() {
                     let ws_address = format!("{WS_PROTOCOL}://{HOSTNAME}/{key}/live");
                     let ws = WebSocket::open(&ws_address).unwrap();
-                    spawn_local_scoped(cx, read_live(ws))
+                    spawn_local_scoped(cx, read_live(ws, registry.data))
                 }
             });
 
+            let is_admin = create_signal(cx, false);
+            spawn_local_scoped(cx, async move {
+                is_admin.set(fetch_role(key).await == Some(Role::Admin));
+            });
+            let admin = device_admin(cx, key, registry);
+
             let show_me = create_signal(cx, false);
 
+            let show_participants = create_signal(cx, true);
+            create_effect_scoped(cx, move |cx| {
+                if *show_participants.get() {
+                    participant_markers(cx, key)
+                }
+            });
+
             create_effect_scoped(cx, |cx| {
                 if *show_me.get() {
                     spawn_local_scoped(cx, my_loc())
@@ -41,14 +79,17 @@ pub fn option_panel(key: &'static str) {
 
             view! {cx,
                 summary {"Opties"}
-                div {"Deze url invullen in je traccar client:"}
-                pre {"https://jotihunt.lucasholten.com/traccar"}
+                div {"Deze url invullen in je traccar client, het token krijg je van een admin:"}
+                pre {"https://jotihunt.lucasholten.com/traccar/<token>"}
                 div(class="field"){
                     label(for="traccar"){"Traccar bekijken:"}
                     input(id="traccar", type="checkbox", bind:checked=show_live)
                     label(for="mijn"){"Mijn locatie:"}
                     input(id="mijn", type="checkbox", bind:checked=show_me)
+                    label(for="deelnemers"){"Deelnemers:"}
+                    input(id="deelnemers", type="checkbox", bind:checked=show_participants)
                 }
+                (if *is_admin.get() { admin.clone() } else { View::empty() })
             }
         },
         &panel,
@@ -61,12 +102,7 @@ async fn my_loc() {
     let _ = PositionStream::new_with_options(options)
         .try_for_each(move |pos| {
             let coords = pos.coords();
-            let m = Marker::new(
-                coords.longitude(),
-                coords.latitude(),
-                "you".to_string(),
-                false,
-            );
+            let m = Marker::new(coords.latitude(), coords.longitude(), "you".to_string());
             m.zoom_to();
             ready(Ok(()))
         })
@@ -74,7 +110,15 @@ async fn my_loc() {
     alert("could not get your location")
 }
 
-async fn read_live(ws: WebSocket) {
+fn describe_device(id: &str, device: Option<&Device>) -> String {
+    match device {
+        Some(device) if !device.team.is_empty() => format!("{} ({})", device.name, device.team),
+        Some(device) => device.name.clone(),
+        None => id.to_owned(),
+    }
+}
+
+async fn read_live(ws: WebSocket, registry: &ReadSignal<BTreeMap<String, Device>>) {
     let mut live_data = HashMap::new();
 
     ws.for_each_concurrent(None, |m| {
@@ -83,14 +127,16 @@ async fn read_live(ws: WebSocket) {
             Message::Bytes(bin) => postcard::from_bytes(&bin).unwrap(),
         };
         console_dbg!(&traccar);
-        let live_loc = Fox {
-            latitude: traccar.lat,
-            longitude: traccar.lon,
-        };
-        let Some(marker) = make_marker(&live_loc, traccar.id.clone()) else {
+        let registry = registry.get();
+        let device = registry.get(&traccar.id);
+        let name = describe_device(&traccar.id, device);
+        let Some(marker) = make_marker(&traccar.position, &name) else {
             return ready(()).boxed_local();
         };
-        marker.set_human();
+        match device.filter(|device| !device.colour.is_empty()) {
+            Some(device) => marker.set_color(&device.colour),
+            None => marker.set_human(),
+        }
         console_dbg!("placed marker");
         let (mut send, receive) = oneshot::channel::<()>();
         live_data.insert(traccar.id, receive);
@@ -107,12 +153,3 @@ async fn read_live(ws: WebSocket) {
     })
     .await
 }
-
-pub fn make_marker(fox: &Fox, name: String) -> Option<Marker> {
-    Some(Marker::new(
-        fox.longitude.parse().ok()?,
-        fox.latitude.parse().ok()?,
-        name,
-        false,
-    ))
-}
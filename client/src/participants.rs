@@ -0,0 +1,47 @@
+use std::rc::Rc;
+
+use jotihunt_shared::domain::Participant;
+use sycamore::prelude::*;
+
+use crate::{comms::live_updated, leaflet::Marker};
+
+fn make_participant(name: &str, participant: &Participant) -> Option<Marker> {
+    let (lat, lon) = participant.position.to_wgs84()?;
+    let place = [&participant.postcode, &participant.city]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let details = [
+        participant.accommodation.clone(),
+        participant.address.clone(),
+        Some(place).filter(|place| !place.is_empty()),
+        Some(format!("{lat}, {lon}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join("\n");
+    let area = participant.area.as_deref().unwrap_or_default();
+    Some(Marker::participant(lat, lon, name, area, &details))
+}
+
+/// Shows the participating groups on the map, the server pushes changes of the api.
+pub fn participant_markers(cx: Scope, key: &'static str) {
+    let participants = live_updated::<String, Participant>(cx, key, "participants").data;
+    let participants = create_memo(cx, || {
+        participants
+            .get()
+            .iter()
+            .map(|(name, participant)| (name.clone(), participant.clone()))
+            .collect::<Vec<_>>()
+    });
+    let markers = map_keyed(
+        cx,
+        participants,
+        |_cx, (name, participant)| Rc::new(make_participant(&name, &participant)),
+        |participant| participant.clone(),
+    );
+    create_memo(cx, || markers.get());
+}
@@ -0,0 +1,415 @@
+//! The boundaries of the deelgebieden, used to shade the map and to spot fox locations
+//! that were entered wrong.
+//!
+//! The boundaries come from a GeoJSON or KML file given at startup. Areas in the api
+//! response that have a `geometry` replace the ones from the file.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{bail, Context};
+use arc_swap::ArcSwap;
+use jotihunt_shared::domain::{AreaCheck, Fox, FoxKey};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+use tracing::error;
+
+use crate::synced::SyncedTree;
+
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    geometry: Geometry,
+    properties: Properties,
+}
+
+#[derive(Deserialize)]
+struct Properties {
+    #[serde(alias = "Name")]
+    name: String,
+}
+
+/// Positions are `[lon, lat]`, optionally followed by the altitude.
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+pub enum Geometry {
+    Polygon(Vec<Vec<Vec<f64>>>),
+    MultiPolygon(Vec<Vec<Vec<Vec<f64>>>>),
+}
+
+/// An outer ring followed by its holes, as `(lon, lat)`.
+#[derive(Clone, PartialEq)]
+struct Polygon(Vec<Vec<(f64, f64)>>);
+
+impl Polygon {
+    fn from_geojson(rings: Vec<Vec<Vec<f64>>>) -> anyhow::Result<Self> {
+        let rings = rings
+            .into_iter()
+            .map(|ring| {
+                ring.into_iter()
+                    .map(|position| match position[..] {
+                        [lon, lat, ..] => Ok((lon, lat)),
+                        _ => bail!("position without latitude"),
+                    })
+                    .collect()
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Polygon(rings))
+    }
+
+    /// Reads a KML `Polygon` element.
+    fn from_kml(polygon: roxmltree::Node) -> anyhow::Result<Self> {
+        let mut rings = vec![];
+        for boundary in ["outerBoundaryIs", "innerBoundaryIs"] {
+            let coordinates = polygon
+                .children()
+                .filter(|node| node.has_tag_name(boundary))
+                .flat_map(|node| node.descendants())
+                .filter(|node| node.has_tag_name("coordinates"));
+            for coordinates in coordinates {
+                // tuples of `lon,lat[,alt]` separated by whitespace
+                let ring = coordinates
+                    .text()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|tuple| {
+                        let mut numbers = tuple.split(',').map(str::parse::<f64>);
+                        match (numbers.next(), numbers.next()) {
+                            (Some(Ok(lon)), Some(Ok(lat))) => Ok((lon, lat)),
+                            _ => bail!("invalid coordinate {tuple:?}"),
+                        }
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                rings.push(ring);
+            }
+        }
+        if rings.is_empty() {
+            bail!("polygon without boundary");
+        }
+        Ok(Polygon(rings))
+    }
+
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        // inside the outer ring and outside the holes means crossing an odd number of edges
+        let mut inside = false;
+        for ring in &self.0 {
+            let edges = ring.iter().zip(ring.iter().cycle().skip(1));
+            for (&(lon1, lat1), &(lon2, lat2)) in edges {
+                if (lat1 > lat) != (lat2 > lat)
+                    && lon < lon1 + (lat - lat1) / (lat2 - lat1) * (lon2 - lon1)
+                {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+
+    fn to_geojson(&self) -> Vec<Vec<[f64; 2]>> {
+        self.0
+            .iter()
+            .map(|ring| ring.iter().map(|&(lon, lat)| [lon, lat]).collect())
+            .collect()
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Area {
+    pub name: String,
+    polygons: Vec<Polygon>,
+}
+
+impl Area {
+    pub fn from_geometry(name: String, geometry: Geometry) -> anyhow::Result<Self> {
+        let polygons = match geometry {
+            Geometry::Polygon(rings) => vec![Polygon::from_geojson(rings)?],
+            Geometry::MultiPolygon(polygons) => polygons
+                .into_iter()
+                .map(Polygon::from_geojson)
+                .collect::<anyhow::Result<_>>()?,
+        };
+        Ok(Area { name, polygons })
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.polygons
+            .iter()
+            .any(|polygon| polygon.contains(lon, lat))
+    }
+}
+
+fn parse_geojson(text: &str) -> anyhow::Result<Vec<Area>> {
+    let collection: FeatureCollection = serde_json::from_str(text)?;
+    collection
+        .features
+        .into_iter()
+        .map(|feature| Area::from_geometry(feature.properties.name, feature.geometry))
+        .collect()
+}
+
+/// Every `Placemark` with a name and polygons is an area.
+fn parse_kml(text: &str) -> anyhow::Result<Vec<Area>> {
+    let document = roxmltree::Document::parse(text)?;
+    let mut areas = vec![];
+    for placemark in document
+        .descendants()
+        .filter(|node| node.has_tag_name("Placemark"))
+    {
+        let name = placemark
+            .children()
+            .find(|node| node.has_tag_name("name"))
+            .and_then(|node| node.text());
+        let Some(name) = name else {
+            continue;
+        };
+        let polygons = placemark
+            .descendants()
+            .filter(|node| node.has_tag_name("Polygon"))
+            .map(Polygon::from_kml)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("area {name}"))?;
+        if !polygons.is_empty() {
+            areas.push(Area {
+                name: name.trim().to_owned(),
+                polygons,
+            });
+        }
+    }
+    Ok(areas)
+}
+
+/// Reads a GeoJSON file with a (multi)polygon feature for every area, or a KML file
+/// with a placemark for every area.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Area>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading areas file {}", path.display()))?;
+    let is_kml = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("kml"));
+    let areas = match is_kml {
+        true => parse_kml(&text),
+        false => parse_geojson(&text),
+    };
+    areas.with_context(|| format!("parsing areas file {}", path.display()))
+}
+
+/// All deelgebieden, looked up by the `fox_name` of a location.
+pub struct Areas {
+    areas: ArcSwap<Vec<Area>>,
+    changed: watch::Sender<()>,
+}
+
+impl Areas {
+    pub fn new(areas: Vec<Area>) -> Self {
+        Self {
+            areas: ArcSwap::from_pointee(areas),
+            changed: watch::Sender::new(()),
+        }
+    }
+
+    /// Adds the area, or replaces the area with the same name, if that is different.
+    pub fn replace(&self, area: Area) {
+        // the api sends every boundary on every poll, the checks only have to run on changes
+        if self.areas.load().contains(&area) {
+            return;
+        }
+        self.areas.rcu(|areas| {
+            let mut areas: Vec<_> = areas
+                .iter()
+                .filter(|old| !old.name.eq_ignore_ascii_case(&area.name))
+                .cloned()
+                .collect();
+            areas.push(area.clone());
+            areas
+        });
+        self.changed.send_replace(());
+    }
+
+    /// Nothing when the location can not be checked: no coordinate or an unknown area.
+    pub fn check(&self, key: &FoxKey, fox: &Fox) -> Option<AreaCheck> {
+        let areas = self.areas.load();
+        let (lat, lon) = fox.coordinate.to_wgs84()?;
+        let own = areas
+            .iter()
+            .find(|area| area.name.eq_ignore_ascii_case(&key.fox_name))?;
+        if own.contains(lat, lon) {
+            return Some(AreaCheck::Inside);
+        }
+        let area = areas.iter().find(|area| area.contains(lat, lon));
+        Some(AreaCheck::Outside {
+            area: area.map(|area| area.name.clone()),
+        })
+    }
+
+    /// The areas as a GeoJSON feature collection, with the latest status of every area.
+    pub fn geojson(&self, status: &SyncedTree) -> anyhow::Result<String> {
+        // the status tree is keyed by `(updated_at, name)`, so the last one wins
+        let mut latest = BTreeMap::new();
+        // a public request, so it does not take a snapshot that would block the pollers
+        for pair in status.iter() {
+            let (key, value) = pair?;
+            let (Ok((_, name)), Ok(status)) = (
+                postcard::from_bytes::<(String, String)>(&key),
+                postcard::from_bytes::<String>(&value),
+            ) else {
+                continue;
+            };
+            latest.insert(name.to_lowercase(), status);
+        }
+
+        let features: Vec<_> = self
+            .areas
+            .load()
+            .iter()
+            .map(|area| {
+                let status = latest.get(&area.name.to_lowercase());
+                let colour = status
+                    .map(String::as_str)
+                    .filter(|status| ["green", "orange", "red"].contains(status))
+                    .unwrap_or("grey");
+                let polygons: Vec<_> = area.polygons.iter().map(Polygon::to_geojson).collect();
+                json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "MultiPolygon",
+                        "coordinates": polygons,
+                    },
+                    "properties": {
+                        "name": area.name,
+                        "status": status,
+                        "colour": colour,
+                    },
+                })
+            })
+            .collect();
+        let geo = json!({
+            "type": "FeatureCollection",
+            "features": features,
+        });
+        Ok(serde_json::to_string(&geo)?)
+    }
+}
+
+/// Makes `checks` contain the [AreaCheck] of every location that can be checked.
+fn check_all(locations: &SyncedTree, checks: &SyncedTree, areas: &Areas) -> anyhow::Result<()> {
+    let mut wanted = BTreeMap::new();
+    for (key, value) in locations.snapshot()?.1 {
+        let (Ok(fox_key), Ok(fox)) = (postcard::from_bytes(&key), postcard::from_bytes(&value))
+        else {
+            continue;
+        };
+        if let Some(check) = areas.check(&fox_key, &fox) {
+            wanted.insert(key, postcard::to_allocvec(&check)?);
+        }
+    }
+    checks.replace_all(wanted)?;
+    Ok(())
+}
+
+/// Checks the locations again after every change of the locations or the areas.
+pub async fn check_locations_loop(locations: &SyncedTree, checks: &SyncedTree, areas: &Areas) {
+    let mut locations_changed = locations.watch_log();
+    let mut areas_changed = areas.changed.subscribe();
+    loop {
+        if let Err(err) = check_all(locations, checks, areas) {
+            error!("error checking locations: {err}");
+        }
+        let res = tokio::select! {
+            res = locations_changed.changed() => res,
+            res = areas_changed.changed() => res,
+        };
+        if res.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jotihunt_shared::coordinate::Coordinate;
+
+    use super::*;
+
+    fn square(name: &str, lon: f64, lat: f64) -> Area {
+        let ring = vec![
+            (lon, lat),
+            (lon + 1.0, lat),
+            (lon + 1.0, lat + 1.0),
+            (lon, lat + 1.0),
+            (lon, lat),
+        ];
+        Area {
+            name: name.to_owned(),
+            polygons: vec![Polygon(vec![ring])],
+        }
+    }
+
+    #[test]
+    fn checks_the_area_of_the_fox() {
+        let areas = Areas::new(vec![square("Alpha", 5.0, 51.0), square("Bravo", 6.0, 51.0)]);
+        let key = |fox_name: &str| FoxKey {
+            day: "2026-10-17".to_owned(),
+            time: "10:00".to_owned(),
+            fox_name: fox_name.to_owned(),
+        };
+        let fox = |lat, lon| Fox {
+            coordinate: Coordinate::wgs84(lat, lon).unwrap(),
+        };
+
+        assert_eq!(
+            areas.check(&key("alpha"), &fox(51.5, 5.5)),
+            Some(AreaCheck::Inside)
+        );
+        assert_eq!(
+            areas.check(&key("Alpha"), &fox(51.5, 6.5)),
+            Some(AreaCheck::Outside {
+                area: Some("Bravo".to_owned())
+            })
+        );
+        assert_eq!(
+            areas.check(&key("Alpha"), &fox(53.0, 5.5)),
+            Some(AreaCheck::Outside { area: None })
+        );
+        assert_eq!(areas.check(&key("Charlie"), &fox(51.5, 5.5)), None);
+        assert_eq!(areas.check(&key("Alpha"), &Fox::default()), None);
+    }
+
+    #[test]
+    fn only_changed_areas_are_replaced() {
+        let areas = Areas::new(vec![square("Alpha", 5.0, 51.0)]);
+        let changed = areas.changed.subscribe();
+        areas.replace(square("Alpha", 5.0, 51.0));
+        assert!(!changed.has_changed().unwrap());
+        areas.replace(square("Alpha", 5.5, 51.0));
+        assert!(changed.has_changed().unwrap());
+    }
+
+    #[test]
+    fn reads_kml() {
+        let kml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <kml xmlns="http://www.opengis.net/kml/2.2"><Document>
+                <Placemark>
+                    <name>Alpha</name>
+                    <Polygon>
+                        <outerBoundaryIs><LinearRing><coordinates>
+                            5,51,0 6,51,0 6,52,0 5,52,0 5,51,0
+                        </coordinates></LinearRing></outerBoundaryIs>
+                        <innerBoundaryIs><LinearRing><coordinates>
+                            5.4,51.4 5.6,51.4 5.6,51.6 5.4,51.6 5.4,51.4
+                        </coordinates></LinearRing></innerBoundaryIs>
+                    </Polygon>
+                </Placemark>
+                <Placemark><name>Start</name><Point><coordinates>5,51</coordinates></Point></Placemark>
+            </Document></kml>"#;
+        let areas = parse_kml(kml).unwrap();
+        assert_eq!(areas.len(), 1);
+        assert_eq!(areas[0].name, "Alpha");
+        assert!(areas[0].contains(51.2, 5.2));
+        assert!(!areas[0].contains(51.5, 5.5));
+        assert!(!areas[0].contains(52.5, 5.5));
+    }
+}
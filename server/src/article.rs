@@ -1,9 +1,12 @@
-use std::time::Duration;
-
 use jotihunt_shared::domain::SavedArticle;
 use serde::Deserialize;
-use sled::Db;
 use tokio::time::sleep;
+use tracing::{debug, error, info_span, Instrument};
+
+use crate::{synced::SyncedTree, upstream::Upstream, AppState};
+
+/// Name of the poller in the health report.
+const POLLER: &str = "articles";
 
 #[derive(Deserialize)]
 struct Articles {
@@ -24,40 +27,44 @@ struct Message {
     content: String,
 }
 
-fn update_single_article(tree: &sled::Tree, article: Article) -> Result<(), postcard::Error> {
+fn update_single_article(tree: &SyncedTree, article: Article) -> anyhow::Result<()> {
     let key = postcard::to_allocvec(&(&article.publish_at, &article.id))?;
     let value = postcard::to_allocvec(&SavedArticle {
         title: article.title,
         r#type: article.r#type,
         content: article.message.content,
     })?;
-    if tree.get(&key).unwrap().as_slice() != Some(&value).as_slice() {
-        let _old = tree.insert(&key, value).unwrap();
-    }
+    tree.insert(&key, &value)?;
     Ok(())
 }
 
-async fn retrieve_articles_inner(tree: &sled::Tree) -> Result<(), reqwest::Error> {
-    let res = reqwest::get("https://jotihunt.nl/api/2.0/articles").await?;
-    let areas: Articles = res.json().await?;
+async fn retrieve_articles_inner(tree: &SyncedTree, upstream: &Upstream) -> anyhow::Result<()> {
+    let areas: Articles = upstream.get("articles").await?;
     for area in areas.data {
         if let Err(err) = update_single_article(tree, area) {
-            println!("error handling article: {err}")
+            error!("error handling article: {err}")
         }
     }
     Ok(())
 }
 
-pub async fn retrieve_articles_loop(db: &Db) {
-    let tree = db.open_tree("articles").unwrap();
-
+pub async fn retrieve_articles_loop(state: AppState) {
+    let interval = state.config.articles_interval();
+    state.health.register(POLLER, interval);
     loop {
-        println!("reloading articles");
-        if let Err(err) = retrieve_articles_inner(&tree).await {
-            println!("error getting article: {err}");
+        async {
+            debug!("reloading articles");
+            match retrieve_articles_inner(&state.articles, &state.upstream).await {
+                Ok(()) => state.health.success(POLLER),
+                Err(err) => {
+                    error!("error getting article: {err}");
+                    state.health.error(POLLER, &err);
+                }
+            }
         }
+        .instrument(info_span!("poll", poller = POLLER))
+        .await;
 
-        // every 5 seconds
-        sleep(Duration::from_secs(5)).await;
+        sleep(interval).await;
     }
 }
@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jotihunt_shared::{AtomicEdit, AuditEntry, AuditKey};
+use sled::Db;
+
+use crate::synced::SyncedTree;
+
+/// Log of all edits made by clients, stored in the `audit` tree ordered by time.
+pub struct Audit {
+    db: Db,
+    tree: SyncedTree,
+}
+
+impl Audit {
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        Ok(Self {
+            db: db.clone(),
+            tree: SyncedTree::open(db, "audit")?.append_only(),
+        })
+    }
+
+    pub fn tree(&self) -> &SyncedTree {
+        &self.tree
+    }
+
+    pub fn record(&self, user: &str, edit: &AtomicEdit, applied: bool) -> anyhow::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let key = postcard::to_allocvec(&AuditKey {
+            time,
+            id: self.db.generate_id()?,
+        })?;
+        let value = postcard::to_allocvec(&AuditEntry {
+            user: user.to_owned(),
+            key: edit.key.clone(),
+            old: edit.old.clone(),
+            new: edit.new.clone(),
+            applied,
+        })?;
+        self.tree.insert(&key, &value)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    fs::read_to_string,
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Extension, Json, RequestExt, Router,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use jotihunt_shared::Role;
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use tokio::sync::Semaphore;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::AppState;
+
+/// A session stops working this long after logging in.
+const SESSION_TTL: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Logins verified at the same time, every verification takes a lot of memory and cpu.
+const CONCURRENT_LOGINS: usize = 4;
+
+/// Checked for unknown users, so they take as long as a wrong password.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::encode_b64(Uuid::nil().as_bytes()).unwrap();
+    Argon2::default()
+        .hash_password(b"not a password", &salt)
+        .unwrap()
+        .to_string()
+});
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    password_hash: String,
+    role: Role,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    user: String,
+    created_at: u64,
+}
+
+impl StoredSession {
+    fn expired(&self) -> bool {
+        self.created_at + SESSION_TTL.as_secs() < now()
+    }
+}
+
+/// The user a websocket or request was authenticated as.
+#[derive(Clone, Debug, Serialize)]
+pub struct Session {
+    pub token: Uuid,
+    pub user: String,
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct UserInfo {
+    name: String,
+    role: Role,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    token: Uuid,
+    user: String,
+    created_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    name: String,
+    password: String,
+    role: Role,
+}
+
+/// User accounts and their session tokens, stored in sled.
+pub struct Auth {
+    users: Tree,
+    sessions: Tree,
+    logins: Semaphore,
+}
+
+impl Auth {
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        Ok(Self {
+            users: db.open_tree("users")?,
+            sessions: db.open_tree("sessions")?,
+            logins: Semaphore::new(CONCURRENT_LOGINS),
+        })
+    }
+
+    /// Creates an admin account with the password in the file if there are no users yet.
+    pub fn bootstrap(&self, password_file: &std::path::Path) -> anyhow::Result<()> {
+        if !self.users.is_empty() {
+            return Ok(());
+        }
+        let password = read_to_string(password_file).with_context(|| {
+            format!(
+                "reading the password of the initial admin from {}",
+                password_file.display()
+            )
+        })?;
+        if password.trim().is_empty() {
+            bail!("password file {} is empty", password_file.display());
+        }
+        info!("creating user 'admin' with the initial password");
+        self.set_user("admin", password.trim(), Role::Admin)
+    }
+
+    /// Creates or changes a user, which revokes their sessions.
+    ///
+    /// Hashing takes a while, call it from a blocking task.
+    pub fn set_user(&self, name: &str, password: &str, role: Role) -> anyhow::Result<()> {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
+            .map_err(|err| anyhow::anyhow!("creating salt: {err}"))?;
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow::anyhow!("hashing password: {err}"))?
+            .to_string();
+        let user = postcard::to_allocvec(&StoredUser {
+            password_hash,
+            role,
+        })?;
+        self.users.insert(name, user)?;
+        self.revoke_user(name)?;
+        Ok(())
+    }
+
+    /// Removes the user and revokes all of their sessions.
+    pub fn remove_user(&self, name: &str) -> sled::Result<bool> {
+        let existed = self.users.remove(name)?.is_some();
+        self.revoke_user(name)?;
+        Ok(existed)
+    }
+
+    fn revoke_user(&self, name: &str) -> sled::Result<()> {
+        for (token, session) in self.sessions.iter().flatten() {
+            let Ok(session) = postcard::from_bytes::<StoredSession>(&session) else {
+                continue;
+            };
+            if session.user == name {
+                self.sessions.remove(token)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn user(&self, name: &str) -> Option<StoredUser> {
+        let user = self.users.get(name).unwrap()?;
+        postcard::from_bytes(&user).ok()
+    }
+
+    pub fn users(&self) -> Vec<UserInfo> {
+        self.users
+            .iter()
+            .flatten()
+            .filter_map(|(name, user)| {
+                let user: StoredUser = postcard::from_bytes(&user).ok()?;
+                Some(UserInfo {
+                    name: String::from_utf8_lossy(&name).into_owned(),
+                    role: user.role,
+                })
+            })
+            .collect()
+    }
+
+    /// Checks the password and creates a new session token.
+    pub async fn login(&self, name: &str, password: String) -> Option<Uuid> {
+        let user = self.user(name);
+        let _permit = self.logins.acquire().await.unwrap();
+        // verifying takes a while on purpose, it should not hold up other requests
+        let verified = tokio::task::spawn_blocking(move || {
+            let hash = user
+                .as_ref()
+                .map_or(&*DUMMY_HASH, |user| &user.password_hash);
+            let hash = PasswordHash::new(hash).ok()?;
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .ok()?;
+            user
+        })
+        .await
+        .unwrap();
+        verified?;
+
+        let token = Uuid::new_v4();
+        let session = postcard::to_allocvec(&StoredSession {
+            user: name.to_owned(),
+            created_at: now(),
+        })
+        .unwrap();
+        self.sessions.insert(token.as_bytes(), session).unwrap();
+        Some(token)
+    }
+
+    /// Looks up a session, the role is read from the user so changes apply immediately.
+    pub fn session(&self, token: Uuid) -> Option<Session> {
+        let session = self.sessions.get(token.as_bytes()).unwrap()?;
+        let session: StoredSession = postcard::from_bytes(&session).ok()?;
+        if session.expired() {
+            self.revoke(token);
+            return None;
+        }
+        let user = self.user(&session.user)?;
+        Some(Session {
+            token,
+            user: session.user,
+            role: user.role,
+        })
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .flatten()
+            .filter_map(|(token, session)| {
+                let session: StoredSession = postcard::from_bytes(&session).ok()?;
+                if session.expired() {
+                    return None;
+                }
+                Some(SessionInfo {
+                    token: Uuid::from_slice(&token).ok()?,
+                    user: session.user,
+                    created_at: session.created_at,
+                })
+            })
+            .collect()
+    }
+
+    pub fn revoke(&self, token: Uuid) -> bool {
+        self.sessions.remove(token.as_bytes()).unwrap().is_some()
+    }
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_owned(), password.to_owned()))
+}
+
+/// Handles basic auth and returns a fresh session token.
+pub async fn login(auth: &Auth, headers: HeaderMap) -> Response {
+    let token = match basic_credentials(&headers) {
+        Some((name, password)) => auth.login(&name, password).await,
+        None => None,
+    };
+    match token {
+        Some(token) => token.to_string().into_response(),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"jotihunt\"")],
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyPath {
+    key: Uuid,
+}
+
+/// Middleware that checks the `{key}` in the path is a valid session token.
+///
+/// The [Session] is added to the request extensions.
+pub async fn validate_session(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Ok(Path(KeyPath { key })) = request.extract_parts().await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Some(session) = state.auth.session(key) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    request.extensions_mut().insert(session);
+    next.run(request).await
+}
+
+/// Middleware that only lets admins through, has to run after [validate_session].
+pub async fn require_admin(
+    Extension(session): Extension<Session>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if session.role < Role::Admin {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+struct NamePath {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct TokenPath {
+    token: Uuid,
+}
+
+/// Routes for managing users and sessions, only accessible for admins.
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/users",
+            get(|State(state): State<AppState>| async move { Json(state.auth.users()) }).post(
+                |State(state): State<AppState>, Json(user): Json<NewUser>| async move {
+                    let saved = tokio::task::spawn_blocking(move || {
+                        state.auth.set_user(&user.name, &user.password, user.role)
+                    });
+                    match saved.await.unwrap() {
+                        Ok(()) => StatusCode::OK,
+                        Err(err) => {
+                            error!("error saving user: {err}");
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        }
+                    }
+                },
+            ),
+        )
+        .route(
+            "/users/{name}",
+            delete(
+                |State(state): State<AppState>, Path(NamePath { name }): Path<NamePath>| async move {
+                    match state.auth.remove_user(&name).unwrap() {
+                        true => StatusCode::OK,
+                        false => StatusCode::NOT_FOUND,
+                    }
+                },
+            ),
+        )
+        .route(
+            "/sessions",
+            get(|State(state): State<AppState>| async move { Json(state.auth.sessions()) }),
+        )
+        .route(
+            "/sessions/{token}",
+            delete(
+                |State(state): State<AppState>, Path(TokenPath { token }): Path<TokenPath>| async move {
+                    match state.auth.revoke(token) {
+                        true => StatusCode::OK,
+                        false => StatusCode::NOT_FOUND,
+                    }
+                },
+            ),
+        )
+        .route_layer(axum::middleware::from_fn(require_admin))
+}
@@ -0,0 +1,49 @@
+//! Serves recorded responses of the jotihunt api, so the server can be run without internet.
+//!
+//! Start the server with `--upstream http://127.0.0.1:8081/api/2.0` to use it.
+//! The json files are read on every request, so they can be edited while running.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::get, Router};
+use clap::Parser;
+
+#[derive(Parser)]
+#[command(version, about = "Fake jotihunt api")]
+struct Args {
+    /// TCP address to listen on
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    listen: SocketAddr,
+    /// Directory with `articles.json`, `areas.json` and `subscriptions.json`
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/fake_api"))]
+    data: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let data: &'static PathBuf = Box::leak(Box::new(args.data));
+
+    let router = Router::new().route(
+        "/api/2.0/{endpoint}",
+        get(move |Path(endpoint): Path<String>| async move {
+            if !matches!(&*endpoint, "articles" | "areas" | "subscriptions") {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let path = data.join(format!("{endpoint}.json"));
+            match tokio::fs::read_to_string(&path).await {
+                Ok(json) => ([("content-type", "application/json")], json).into_response(),
+                Err(err) => {
+                    println!("error reading {}: {err}", path.display());
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
+        }),
+    );
+
+    println!("serving {} on http://{}", data.display(), args.listen);
+    let listener = tokio::net::TcpListener::bind(args.listen).await?;
+    axum::serve(listener, router).await?;
+
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+/// Command line arguments, these override the values in the config file.
+#[derive(Parser)]
+#[command(version, about = "Jotihunt map server")]
+pub struct Args {
+    /// Path to a TOML config file
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+    /// Path to the sled database
+    #[arg(long)]
+    db: Option<PathBuf>,
+    /// Unix socket to listen on
+    #[arg(long, conflicts_with = "listen")]
+    socket: Option<PathBuf>,
+    /// TCP address to listen on instead of the unix socket
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    /// File with the password of the initial admin account, only read when there are no users
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+    /// Base url of the jotihunt api
+    #[arg(long)]
+    upstream: Option<String>,
+    /// Seconds before a request to the jotihunt api times out
+    #[arg(long)]
+    upstream_timeout: Option<u64>,
+    /// User agent sent to the jotihunt api
+    #[arg(long)]
+    user_agent: Option<String>,
+    /// Seconds a client gets to send its version and revision after connecting
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+    /// Seconds between reloading articles
+    #[arg(long)]
+    articles_interval: Option<u64>,
+    /// Seconds between reloading the area status
+    #[arg(long)]
+    status_interval: Option<u64>,
+    /// Seconds between reloading the participants
+    #[arg(long)]
+    geojson_interval: Option<u64>,
+    /// Leave out participants whose name contains this word, can be given multiple times
+    #[arg(long = "exclude-group")]
+    exclude_groups: Vec<String>,
+    /// Which logs to show, like "info" or "warn,server=debug", RUST_LOG takes precedence
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Write the logs for humans or as JSON lines
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+    /// GeoJSON or KML file with the boundaries of the areas, to check the fox locations
+    #[arg(long)]
+    areas_file: Option<PathBuf>,
+    /// TCP address to serve the Prometheus metrics on, like 127.0.0.1:9100
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub db: PathBuf,
+    pub socket: PathBuf,
+    pub listen: Option<SocketAddr>,
+    pub password_file: PathBuf,
+    pub upstream: String,
+    pub upstream_timeout: u64,
+    pub user_agent: String,
+    pub handshake_timeout: u64,
+    pub articles_interval: u64,
+    pub status_interval: u64,
+    pub geojson_interval: u64,
+    /// Participants whose name contains one of these words are not shown, ignoring case
+    pub exclude_groups: Vec<String>,
+    pub areas_file: Option<PathBuf>,
+    /// A tracing filter directive
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Where `/metrics` is served, apart from the rest because it shows the device names
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db: "joti.db".into(),
+            socket: "/run/jotihunt/socket".into(),
+            listen: None,
+            password_file: "password".into(),
+            upstream: "https://jotihunt.nl/api/2.0".to_owned(),
+            upstream_timeout: 30,
+            user_agent: concat!("jotihunt-server/", env!("CARGO_PKG_VERSION")).to_owned(),
+            handshake_timeout: 10,
+            // every 5 seconds
+            articles_interval: 5,
+            // every minute
+            status_interval: 60,
+            // every hour
+            geojson_interval: 60 * 60,
+            // the organisation registers test groups
+            exclude_groups: vec!["test".to_owned()],
+            areas_file: None,
+            log_level: "info".to_owned(),
+            log_format: LogFormat::Human,
+            metrics_listen: None,
+        }
+    }
+}
+
+pub enum Listen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl Config {
+    /// Reads the config file (if any) and applies the command line arguments on top.
+    pub fn load() -> anyhow::Result<Self> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading config file {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("parsing config file {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(db) = args.db {
+            config.db = db;
+        }
+        if let Some(socket) = args.socket {
+            config.socket = socket;
+            config.listen = None;
+        }
+        if let Some(listen) = args.listen {
+            config.listen = Some(listen);
+        }
+        if let Some(password_file) = args.password_file {
+            config.password_file = password_file;
+        }
+        if let Some(upstream) = args.upstream {
+            config.upstream = upstream;
+        }
+        if let Some(secs) = args.upstream_timeout {
+            config.upstream_timeout = secs;
+        }
+        if let Some(user_agent) = args.user_agent {
+            config.user_agent = user_agent;
+        }
+        if let Some(secs) = args.handshake_timeout {
+            config.handshake_timeout = secs;
+        }
+        if let Some(secs) = args.articles_interval {
+            config.articles_interval = secs;
+        }
+        if let Some(secs) = args.status_interval {
+            config.status_interval = secs;
+        }
+        if let Some(secs) = args.geojson_interval {
+            config.geojson_interval = secs;
+        }
+        if !args.exclude_groups.is_empty() {
+            config.exclude_groups = args.exclude_groups;
+        }
+        if let Some(areas_file) = args.areas_file {
+            config.areas_file = Some(areas_file);
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = args.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(metrics_listen) = args.metrics_listen {
+            config.metrics_listen = Some(metrics_listen);
+        }
+
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Rejects values the server can not run with, a zero interval would panic later on.
+    fn check(&self) -> anyhow::Result<()> {
+        for (key, secs) in [
+            ("upstream_timeout", self.upstream_timeout),
+            ("handshake_timeout", self.handshake_timeout),
+            ("articles_interval", self.articles_interval),
+            ("status_interval", self.status_interval),
+            ("geojson_interval", self.geojson_interval),
+        ] {
+            if secs == 0 {
+                bail!("{key} must be at least 1 second");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn listen(&self) -> Listen {
+        match self.listen {
+            Some(addr) => Listen::Tcp(addr),
+            None => Listen::Unix(self.socket.clone()),
+        }
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    pub fn articles_interval(&self) -> Duration {
+        Duration::from_secs(self.articles_interval)
+    }
+
+    pub fn status_interval(&self) -> Duration {
+        Duration::from_secs(self.status_interval)
+    }
+
+    pub fn geojson_interval(&self) -> Duration {
+        Duration::from_secs(self.geojson_interval)
+    }
+}
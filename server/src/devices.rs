@@ -0,0 +1,259 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get},
+    Json, Router,
+};
+use jotihunt_shared::Traccar;
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use uuid::Uuid;
+
+use crate::{auth::require_admin, AppState};
+
+#[derive(Serialize, Deserialize)]
+struct StoredDevice {
+    token: Uuid,
+}
+
+/// At most this many devices wait for approval, `/traccar` accepts reports from anyone.
+const MAX_PENDING: usize = 50;
+
+/// A device that did not report for this long stops waiting for approval.
+const PENDING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct PendingDevice {
+    /// Unix milliseconds, the time in the report is chosen by the device
+    received: u64,
+    traccar: Traccar,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    id: String,
+    token: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct NewDevice {
+    id: String,
+}
+
+/// The traccar devices that are allowed to report positions, each with its own token.
+///
+/// Reports of unknown devices are kept in `devices.pending`, so an admin can approve them.
+pub struct Devices {
+    devices: Tree,
+    /// The id of the device of every token
+    tokens: Tree,
+    pending: Tree,
+}
+
+impl Devices {
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        Ok(Self {
+            devices: db.open_tree("devices")?,
+            tokens: db.open_tree("devices.tokens")?,
+            pending: db.open_tree("devices.pending")?,
+        })
+    }
+
+    /// Registers the device with a new token, an old token stops working.
+    pub fn approve(&self, id: &str) -> sled::Result<Uuid> {
+        let token = Uuid::new_v4();
+        let device = postcard::to_allocvec(&StoredDevice { token }).unwrap();
+        self.tokens.insert(token.as_bytes(), id)?;
+        if let Some(old) = self.devices.insert(id, device)? {
+            self.remove_token(&old)?;
+        }
+        self.pending.remove(id)?;
+        Ok(token)
+    }
+
+    pub fn remove(&self, id: &str) -> sled::Result<bool> {
+        let Some(old) = self.devices.remove(id)? else {
+            return Ok(false);
+        };
+        self.remove_token(&old)?;
+        Ok(true)
+    }
+
+    fn remove_token(&self, device: &[u8]) -> sled::Result<()> {
+        if let Ok(device) = postcard::from_bytes::<StoredDevice>(device) {
+            self.tokens.remove(device.token.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices
+            .iter()
+            .flatten()
+            .filter_map(|(id, device)| {
+                let device: StoredDevice = postcard::from_bytes(&device).ok()?;
+                Some(DeviceInfo {
+                    id: String::from_utf8_lossy(&id).into_owned(),
+                    token: device.token,
+                })
+            })
+            .collect()
+    }
+
+    /// The id of the device the token belongs to.
+    pub fn device(&self, token: Uuid) -> Option<String> {
+        let id = self.tokens.get(token.as_bytes()).ok()??;
+        Some(String::from_utf8_lossy(&id).into_owned())
+    }
+
+    /// Remembers the last report of a device that is not approved yet.
+    ///
+    /// Devices that stopped reporting are forgotten, and when too many are waiting the one
+    /// that reported longest ago makes room.
+    pub fn add_pending(&self, traccar: &Traccar) -> anyhow::Result<()> {
+        let received = now();
+        let expired = received.saturating_sub(PENDING_TTL.as_millis() as u64);
+        let mut waiting = vec![];
+        for pair in self.pending.iter() {
+            let (id, pending) = pair?;
+            // entries that can not be read count as the oldest
+            let time = postcard::from_bytes::<PendingDevice>(&pending).map_or(0, |p| p.received);
+            if time < expired {
+                self.pending.remove(&id)?;
+            } else if *id != *traccar.id.as_bytes() {
+                waiting.push((time, id));
+            }
+        }
+        if waiting.len() >= MAX_PENDING {
+            waiting.sort_unstable();
+            for (_, id) in &waiting[..=waiting.len() - MAX_PENDING] {
+                self.pending.remove(id)?;
+            }
+        }
+        let pending = PendingDevice {
+            received,
+            traccar: traccar.clone(),
+        };
+        self.pending
+            .insert(&traccar.id, postcard::to_allocvec(&pending)?)?;
+        Ok(())
+    }
+
+    /// The last report of every device that is waiting for approval.
+    pub fn pending(&self) -> Vec<Traccar> {
+        self.pending
+            .iter()
+            .flatten()
+            .filter_map(|(_, pending)| postcard::from_bytes::<PendingDevice>(&pending).ok())
+            .map(|pending| pending.traccar)
+            .collect()
+    }
+
+    pub fn dismiss(&self, id: &str) -> sled::Result<bool> {
+        Ok(self.pending.remove(id)?.is_some())
+    }
+}
+
+#[derive(Deserialize)]
+struct IdPath {
+    id: String,
+}
+
+/// Routes for approving and removing devices, only accessible for admins.
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/devices",
+            get(|State(state): State<AppState>| async move { Json(state.devices.devices()) }).post(
+                |State(state): State<AppState>, Json(device): Json<NewDevice>| async move {
+                    let token = state.devices.approve(&device.id).unwrap();
+                    Json(DeviceInfo {
+                        id: device.id,
+                        token,
+                    })
+                },
+            ),
+        )
+        .route(
+            "/devices/{id}",
+            delete(
+                |State(state): State<AppState>, Path(IdPath { id }): Path<IdPath>| async move {
+                    match state.devices.remove(&id).unwrap() {
+                        true => StatusCode::OK,
+                        false => StatusCode::NOT_FOUND,
+                    }
+                },
+            ),
+        )
+        .route(
+            "/pending",
+            get(|State(state): State<AppState>| async move { Json(state.devices.pending()) }),
+        )
+        .route(
+            "/pending/{id}",
+            delete(
+                |State(state): State<AppState>, Path(IdPath { id }): Path<IdPath>| async move {
+                    match state.devices.dismiss(&id).unwrap() {
+                        true => StatusCode::OK,
+                        false => StatusCode::NOT_FOUND,
+                    }
+                },
+            ),
+        )
+        .route_layer(axum::middleware::from_fn(require_admin))
+}
+
+#[cfg(test)]
+mod tests {
+    use jotihunt_shared::coordinate::Coordinate;
+
+    use super::*;
+
+    fn report(id: &str) -> Traccar {
+        Traccar {
+            id: id.to_owned(),
+            position: Coordinate::wgs84(52.0, 5.9).unwrap(),
+            time: 0,
+            speed: None,
+            bearing: None,
+            altitude: None,
+            accuracy: None,
+            battery: None,
+        }
+    }
+
+    #[test]
+    fn pending_devices_are_capped() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let devices = Devices::open(&db).unwrap();
+        for i in 0..MAX_PENDING + 10 {
+            devices
+                .add_pending(&report(&format!("device {i}")))
+                .unwrap();
+        }
+        let pending = devices.pending();
+        assert_eq!(pending.len(), MAX_PENDING);
+        assert!(!pending.iter().any(|traccar| traccar.id == "device 0"));
+    }
+
+    #[test]
+    fn tokens_are_indexed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let devices = Devices::open(&db).unwrap();
+        let old = devices.approve("phone").unwrap();
+        let new = devices.approve("phone").unwrap();
+        assert_eq!(devices.device(old), None);
+        assert_eq!(devices.device(new).as_deref(), Some("phone"));
+        devices.remove("phone").unwrap();
+        assert_eq!(devices.device(new), None);
+    }
+}
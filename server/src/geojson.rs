@@ -1,71 +1,318 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use arc_swap::ArcSwap;
-use serde::Deserialize;
-use serde_json::json;
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use jotihunt_shared::{coordinate::Coordinate, domain::Participant};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::time::sleep;
+use tracing::{debug, error, info_span, warn, Instrument};
+
+use crate::{synced::SyncedTree, upstream::RETRY_INTERVAL, AppState};
+
+/// Name of the poller in the health report.
+const POLLER: &str = "subscriptions";
 
 #[derive(Deserialize)]
 struct Subscriptions {
     data: Vec<Group>,
 }
 
+/// A group as the api returns it, most fields are null for groups that did not fill them in.
 #[derive(Deserialize)]
 struct Group {
     name: String,
+    // sic
+    accomodation: Option<String>,
+    street: Option<String>,
+    housenumber: Option<u32>,
+    housenumber_addition: Option<String>,
+    postcode: Option<String>,
+    city: Option<String>,
     lat: String,
     long: String,
     area: Option<String>,
 }
 
-async fn get_geo() -> reqwest::Result<String> {
-    let res = reqwest::get("https://jotihunt.nl/api/2.0/subscriptions").await?;
-    let sub: Subscriptions = res.json().await?;
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct FeatureCollection<'a> {
+    features: Vec<Feature<'a>>,
+}
 
-    let mut features = vec![];
-    for group in sub.data {
-        if group.name.to_lowercase().contains("test") {
-            continue;
-        }
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct Feature<'a> {
+    geometry: Point,
+    properties: Properties<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+struct Point {
+    /// `[lon, lat]`
+    coordinates: [f64; 2],
+}
 
-        features.push(json!({
-            "type": "Feature",
-            "geometry": {
-                "type": "Point",
-                "coordinates": [group.long, group.lat]
-            },
-            "properties": {
-                "name": group.name,
-                "area": group.area,
-            },
-        }))
+#[derive(Serialize)]
+struct Properties<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accommodation: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    postcode: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    city: &'a Option<String>,
+    area: &'a Option<String>,
+}
+
+impl Group {
+    /// Street and house number, like "Heideweg 12a".
+    fn address(&self) -> Option<String> {
+        let street = self.street.as_deref()?.trim();
+        let number = match (self.housenumber, &self.housenumber_addition) {
+            (Some(number), Some(addition)) => format!("{number}{}", addition.trim()),
+            (Some(number), None) => number.to_string(),
+            (None, _) => String::new(),
+        };
+        let address = format!("{street} {number}").trim().to_owned();
+        (!address.is_empty()).then_some(address)
     }
-    let geo = json!({
-        "type": "FeatureCollection",
-        "features": features
-    });
 
-    Ok(serde_json::to_string(&geo).unwrap())
+    fn into_participant(self) -> Option<(String, Participant)> {
+        let position = match (self.lat.trim().parse(), self.long.trim().parse()) {
+            (Ok(lat), Ok(lon)) => Coordinate::wgs84(lat, lon).ok(),
+            _ => None,
+        };
+        let Some(position) = position else {
+            warn!("group {:?} has an invalid position", self.name);
+            return None;
+        };
+        let non_empty = |field: Option<String>| field.filter(|field| !field.trim().is_empty());
+        let address = self.address();
+        let participant = Participant {
+            position,
+            accommodation: non_empty(self.accomodation),
+            address,
+            postcode: non_empty(self.postcode),
+            city: non_empty(self.city),
+            area: self.area,
+        };
+        Some((self.name, participant))
+    }
 }
 
-async fn reload_geojson(geo: Arc<ArcSwap<String>>) {
+/// Groups whose name contains one of the `exclude` words (ignoring case) are left out.
+fn excluded(name: &str, exclude: &[String]) -> bool {
+    let name = name.to_lowercase();
+    exclude
+        .iter()
+        .any(|word| name.contains(&word.to_lowercase()))
+}
+
+/// All participants by name.
+fn participants(sub: Subscriptions, exclude: &[String]) -> BTreeMap<String, Participant> {
+    sub.data
+        .into_iter()
+        .filter(|group| !excluded(&group.name, exclude))
+        .filter_map(Group::into_participant)
+        .collect()
+}
+
+fn to_geojson(participants: &BTreeMap<String, Participant>) -> String {
+    let features = participants
+        .iter()
+        .filter_map(|(name, participant)| {
+            let (lat, lon) = participant.position.to_wgs84()?;
+            Some(Feature {
+                geometry: Point {
+                    coordinates: [lon, lat],
+                },
+                properties: Properties {
+                    name,
+                    accommodation: &participant.accommodation,
+                    address: &participant.address,
+                    postcode: &participant.postcode,
+                    city: &participant.city,
+                    area: &participant.area,
+                },
+            })
+        })
+        .collect();
+    serde_json::to_string(&FeatureCollection { features }).unwrap()
+}
+
+/// Stores the participants in the tree, so connected clients see the changes.
+fn store(tree: &SyncedTree, participants: &BTreeMap<String, Participant>) -> anyhow::Result<()> {
+    let mut entries = BTreeMap::new();
+    for (name, participant) in participants {
+        entries.insert(
+            postcard::to_allocvec(name)?,
+            postcard::to_allocvec(participant)?,
+        );
+    }
+    tree.replace_all(entries)?;
+    Ok(())
+}
+
+/// The participants as GeoJSON, with the validators for conditional requests.
+pub struct Participants {
+    body: String,
+    /// Without the quotes
+    etag: String,
+    last_modified: SystemTime,
+}
+
+/// Whether one of the entity tags in an `If-None-Match` header is `etag` (without quotes).
+///
+/// This is the weak comparison of RFC 9110, a `W/` prefix does not matter.
+fn none_match(header: &str, etag: &str) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    let mut rest = header;
     loop {
-        // every hour
-        sleep(Duration::from_secs(60 * 60)).await;
-        println!("reloading geojson");
-        match get_geo().await {
-            Ok(new) => geo.swap(Arc::new(new)),
-            Err(err) => {
-                println!("error getting geojson: {err}");
-                continue;
-            }
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return false;
+        }
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        // tags can contain commas, so they are read up to the closing quote
+        let Some((tag, after)) = tag.strip_prefix('"').and_then(|tag| tag.split_once('"')) else {
+            return false;
         };
+        if tag == etag {
+            return true;
+        }
+        rest = after;
     }
 }
 
-pub async fn get_reloading_geojson() -> Arc<ArcSwap<String>> {
-    let geojson = get_geo().await.unwrap();
-    let geojson = Arc::new(ArcSwap::new(Arc::new(geojson)));
-    tokio::spawn(reload_geojson(geojson.clone()));
-    geojson
+impl Participants {
+    fn new(body: String) -> Self {
+        // the start of the hash is plenty to tell versions apart
+        let mut etag = String::new();
+        for byte in &Sha256::digest(&body)[..8] {
+            write!(etag, "{byte:02x}").unwrap();
+        }
+        Self {
+            etag,
+            body,
+            last_modified: SystemTime::now(),
+        }
+    }
+
+    fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        // If-Modified-Since is ignored when there is an If-None-Match
+        if let Some(etags) = headers.get(header::IF_NONE_MATCH) {
+            let etags = etags.to_str().unwrap_or_default();
+            return none_match(etags, &self.etag);
+        }
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| httpdate::parse_http_date(since).ok());
+        // http dates have whole seconds
+        since.is_some_and(|since| since + Duration::from_secs(1) > self.last_modified)
+    }
+
+    /// The GeoJSON, or 304 Not Modified when the client already has this version.
+    pub fn respond(&self, headers: &HeaderMap) -> Response {
+        let validators = [
+            (header::ETAG, format!("\"{}\"", self.etag)),
+            (
+                header::LAST_MODIFIED,
+                httpdate::fmt_http_date(self.last_modified),
+            ),
+        ];
+        if self.is_fresh(headers) {
+            return (StatusCode::NOT_MODIFIED, validators).into_response();
+        }
+        let content_type = [(header::CONTENT_TYPE, "application/geo+json".to_owned())];
+        (validators, content_type, self.body.clone()).into_response()
+    }
+}
+
+impl Default for Participants {
+    fn default() -> Self {
+        Self::new(to_geojson(&BTreeMap::new()))
+    }
+}
+
+/// Stores the participants in the tree and as GeoJSON.
+fn apply(state: &AppState, participants: &BTreeMap<String, Participant>) {
+    if let Err(err) = store(&state.participants, participants) {
+        error!("error storing participants: {err}");
+    }
+    let new = to_geojson(participants);
+    // keep the old version, so its Last-Modified stays the same
+    if new != state.geojson.load().body {
+        state.geojson.store(Arc::new(Participants::new(new)));
+    }
+}
+
+async fn reload_geojson(state: AppState) {
+    let interval = state.config.geojson_interval();
+    loop {
+        let failed = async {
+            debug!("reloading geojson");
+            let sub = match state.upstream.get_and_cache("subscriptions").await {
+                Ok(sub) => sub,
+                Err(err) => {
+                    error!("error getting geojson: {err}");
+                    state.health.error(POLLER, &err);
+                    return true;
+                }
+            };
+            state.health.success(POLLER);
+            apply(&state, &participants(sub, &state.config.exclude_groups));
+            false
+        }
+        .instrument(info_span!("poll", poller = POLLER))
+        .await;
+
+        sleep(match failed {
+            true => interval.min(RETRY_INTERVAL),
+            false => interval,
+        })
+        .await;
+    }
+}
+
+/// Loads the participants from the last good response, and reloads them from the api in the
+/// background every geojson interval.
+pub fn get_reloading_geojson(state: &AppState) {
+    state
+        .health
+        .register(POLLER, state.config.geojson_interval());
+    // without a cache the tree keeps what it had
+    if let Some(sub) = state.upstream.cached("subscriptions") {
+        apply(state, &participants(sub, &state.config.exclude_groups));
+    }
+    tokio::spawn(reload_geojson(state.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_entity_tags() {
+        assert!(none_match("\"abc\"", "abc"));
+        assert!(none_match("W/\"abc\"", "abc"));
+        assert!(none_match("\"x,y\", W/\"abc\"", "abc"));
+        assert!(none_match(" * ", "abc"));
+        assert!(!none_match("\"x,abc\"", "abc"));
+        assert!(!none_match("abc", "abc"));
+        assert!(!none_match("", "abc"));
+    }
 }
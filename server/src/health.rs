@@ -0,0 +1,166 @@
+//! How the pollers of the jotihunt api are doing, served on `/health`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::metrics::Exposition;
+
+/// A poller is stale when it has not succeeded for this long on top of twice its interval.
+const STALE_MARGIN: Duration = Duration::from_secs(60);
+
+struct Poller {
+    interval: Duration,
+    /// A poller that never succeeded is stale from this time on
+    registered: SystemTime,
+    last_success: Option<SystemTime>,
+    last_error: Option<(SystemTime, String)>,
+    successes: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+pub struct Health {
+    pollers: Mutex<BTreeMap<&'static str, Poller>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// "ok", or "degraded" when a poller is stale
+    status: &'static str,
+    pollers: BTreeMap<&'static str, PollerReport>,
+}
+
+#[derive(Serialize)]
+struct PollerReport {
+    interval_seconds: u64,
+    /// Unix seconds
+    last_success: Option<u64>,
+    last_error: Option<ErrorReport>,
+    seconds_since_success: Option<u64>,
+    stale: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    /// Unix seconds
+    at: u64,
+    message: String,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Health {
+    pub fn register(&self, name: &'static str, interval: Duration) {
+        self.pollers.lock().unwrap().insert(
+            name,
+            Poller {
+                interval,
+                registered: SystemTime::now(),
+                last_success: None,
+                last_error: None,
+                successes: 0,
+                errors: 0,
+            },
+        );
+    }
+
+    pub fn success(&self, name: &'static str) {
+        if let Some(poller) = self.pollers.lock().unwrap().get_mut(name) {
+            poller.last_success = Some(SystemTime::now());
+            poller.successes += 1;
+        }
+    }
+
+    pub fn error(&self, name: &'static str, err: &impl Display) {
+        if let Some(poller) = self.pollers.lock().unwrap().get_mut(name) {
+            poller.last_error = Some((SystemTime::now(), err.to_string()));
+            poller.errors += 1;
+        }
+    }
+
+    pub fn metrics(&self, out: &mut Exposition) {
+        let pollers = self.pollers.lock().unwrap();
+        out.family(
+            "jotihunt_polls_total",
+            "counter",
+            "Polls of the jotihunt api, by whether they succeeded.",
+        );
+        for (name, poller) in pollers.iter() {
+            out.sample(
+                "jotihunt_polls_total",
+                &[("poller", name), ("result", "success")],
+                poller.successes,
+            );
+            out.sample(
+                "jotihunt_polls_total",
+                &[("poller", name), ("result", "error")],
+                poller.errors,
+            );
+        }
+        out.family(
+            "jotihunt_poller_last_success_timestamp_seconds",
+            "gauge",
+            "When the poller last succeeded, in unix seconds.",
+        );
+        for (name, poller) in pollers.iter() {
+            if let Some(time) = poller.last_success {
+                out.sample(
+                    "jotihunt_poller_last_success_timestamp_seconds",
+                    &[("poller", name)],
+                    unix_seconds(time),
+                );
+            }
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let now = SystemTime::now();
+        let pollers: BTreeMap<_, _> = self
+            .pollers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, poller)| {
+                let since_success = poller
+                    .last_success
+                    .map(|time| now.duration_since(time).unwrap_or_default());
+                let since_start = now
+                    .duration_since(poller.last_success.unwrap_or(poller.registered))
+                    .unwrap_or_default();
+                let stale = since_start > poller.interval * 2 + STALE_MARGIN;
+                let report = PollerReport {
+                    interval_seconds: poller.interval.as_secs(),
+                    last_success: poller.last_success.map(unix_seconds),
+                    last_error: poller.last_error.as_ref().map(|(at, message)| ErrorReport {
+                        at: unix_seconds(*at),
+                        message: message.clone(),
+                    }),
+                    seconds_since_success: since_success.map(|since| since.as_secs()),
+                    stale,
+                };
+                (*name, report)
+            })
+            .collect();
+        let degraded = pollers.values().any(|poller| poller.stale);
+        HealthReport {
+            status: if degraded { "degraded" } else { "ok" },
+            pollers,
+        }
+    }
+}
+
+impl HealthReport {
+    pub fn degraded(&self) -> bool {
+        self.status != "ok"
+    }
+}
@@ -0,0 +1,236 @@
+mod areas;
+mod article;
+mod audit;
+mod auth;
+pub mod config;
+mod devices;
+mod geojson;
+mod health;
+pub mod listen;
+mod live;
+pub mod logging;
+mod metrics;
+mod migrate;
+pub mod shutdown;
+mod state;
+mod status;
+mod sync;
+mod synced;
+mod traccar;
+mod upstream;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use auth::Session;
+use axum::{
+    body::Bytes,
+    extract::{Json, Path, Query, State, WebSocketUpgrade},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{any, get, MethodRouter},
+    Extension, Router,
+};
+use jotihunt_shared::Role;
+use synced::SyncedTree;
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::error;
+use uuid::Uuid;
+
+pub use state::AppState;
+
+/// All routes of the server.
+pub fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/secret",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    auth::login(&state.auth, headers).await
+                },
+            )
+            .route_layer(CorsLayer::very_permissive()),
+        )
+        .nest(
+            "/{key}",
+            Router::new()
+                .route("/locations", synced_route(|s| &s.locations, Some(Role::Editor), true))
+                // only the pollers of the jotihunt api write these
+                .route("/status", synced_route(|s| &s.status, None, false))
+                .route("/articles", synced_route(|s| &s.articles, None, false))
+                .route("/registry", synced_route(|s| &s.registry, Some(Role::Admin), false))
+                .route("/checks", synced_route(|s| &s.checks, None, false))
+                .route("/participants", synced_route(|s| &s.participants, None, false))
+                .route("/fox_list", synced_route(|s| &s.fox_list_tree, None, false))
+                .route("/audit", synced_route(|s| s.audit.tree(), None, false))
+                .route(
+                    "/live",
+                    get(
+                        |State(state): State<AppState>,
+                         Extension(session): Extension<Session>,
+                         req: WebSocketUpgrade| async move {
+                            live::upgrade(req, state, session)
+                        },
+                    ),
+                )
+                .route(
+                    "/track/{device}",
+                    get(
+                        |State(state): State<AppState>,
+                         Extension(session): Extension<Session>,
+                         Path(DevicePath { device }): Path<DevicePath>,
+                         Query(range): Query<TrackRange>| async move {
+                            // viewers only see where the hunters are now
+                            if session.role < Role::Editor {
+                                return StatusCode::FORBIDDEN.into_response();
+                            }
+                            let Some((from, to)) = range.bounds() else {
+                                let message = "the range can be at most 48 hours";
+                                return (StatusCode::BAD_REQUEST, message).into_response();
+                            };
+                            Json(state.live.track(&device, from, to)).into_response()
+                        },
+                    ),
+                )
+                .route(
+                    "/session",
+                    get(|Extension(session): Extension<Session>| async move { Json(session) })
+                        .delete(
+                            |State(state): State<AppState>,
+                             Extension(session): Extension<Session>| async move {
+                                state.auth.revoke(session.token);
+                                StatusCode::OK
+                            },
+                        ),
+                )
+                .nest(
+                    "/admin",
+                    auth::admin_router().merge(devices::admin_router()),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::validate_session,
+                )),
+        )
+        .route("/traccar", any(traccar))
+        .route("/traccar/{token}", any(traccar))
+        .route(
+            "/deelnemers.geojson",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    state.geojson.load().respond(&headers)
+                },
+            )
+            .route_layer(CorsLayer::very_permissive()),
+        )
+        .route(
+            "/areas.geojson",
+            get(|State(state): State<AppState>| async move {
+                match state.areas.geojson(&state.status) {
+                    Ok(geojson) => ([(header::CONTENT_TYPE, "application/geo+json")], geojson)
+                        .into_response(),
+                    Err(err) => {
+                        error!("error making areas geojson: {err}");
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                }
+            })
+            .route_layer(CorsLayer::very_permissive()),
+        )
+        .route(
+            "/health",
+            get(|State(state): State<AppState>| async move {
+                let report = state.health.report();
+                // monitors only look at the status code
+                let status = match report.degraded() {
+                    true => StatusCode::SERVICE_UNAVAILABLE,
+                    false => StatusCode::OK,
+                };
+                (status, Json(report))
+            }),
+        )
+        .route(
+            "/fox_list.json",
+            get(|State(state): State<AppState>| async move {
+                state.fox_list.load().as_ref().clone()
+            })
+            .route_layer(CorsLayer::very_permissive()),
+        )
+        // the last layer is the outermost, the request id is set before the span is made
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state)
+}
+
+/// A websocket route that syncs the `tree` of the state, editable from the `edit` role on.
+///
+/// Edits are recorded in the audit log if `audit`.
+fn synced_route(
+    tree: fn(&AppState) -> &SyncedTree,
+    edit: Option<Role>,
+    audit: bool,
+) -> MethodRouter<AppState> {
+    get(
+        move |State(state): State<AppState>,
+              Extension(session): Extension<Session>,
+              req: WebSocketUpgrade| async move {
+            sync::upgrade(req, state, tree, session, edit, audit)
+        },
+    )
+}
+
+/// Position reports, devices send their token in the path.
+async fn traccar(
+    State(state): State<AppState>,
+    token: Option<Path<Uuid>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let (live, devices, metrics) = (&state.live, &state.devices, &state.metrics);
+    let token = token.map(|Path(token)| token);
+    traccar::receive(live, devices, metrics, token, uri, headers, body).await
+}
+
+/// Serves `/metrics`, on its own address so it is not public.
+pub fn metrics_router(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/metrics",
+            get(|State(state): State<AppState>| async move {
+                let content_type = "text/plain; version=0.0.4; charset=utf-8";
+                let metrics = state.metrics.render(&state.health);
+                ([(header::CONTENT_TYPE, content_type)], metrics)
+            }),
+        )
+        .with_state(state)
+}
+
+#[derive(serde::Deserialize)]
+struct DevicePath {
+    device: String,
+}
+
+/// Time range of a track in milliseconds since the unix epoch, both ends are optional.
+#[derive(serde::Deserialize)]
+struct TrackRange {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+impl TrackRange {
+    /// The range, by default the last [live::MAX_TRACK] up to now, or nothing if it is longer.
+    fn bounds(&self) -> Option<(u64, u64)> {
+        let max = live::MAX_TRACK.as_millis() as u64;
+        let to = self.to.unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            now.as_millis() as u64
+        });
+        let from = self.from.unwrap_or(to.saturating_sub(max));
+        (to.saturating_sub(from) <= max).then_some((from, to))
+    }
+}
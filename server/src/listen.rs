@@ -0,0 +1,94 @@
+//! The socket the server listens on: from systemd, a unix socket or tcp.
+
+use std::{
+    env,
+    fs::{remove_file, set_permissions, Permissions},
+    io::ErrorKind,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::fs::{FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{info, warn};
+
+use crate::config::{Config, Listen};
+
+/// The first file descriptor systemd passes, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    /// With the path of the socket file if this server created it, to remove it when stopping
+    Unix(UnixListener, Option<PathBuf>),
+    Tcp(TcpListener),
+}
+
+/// The socket systemd passed, when the server is socket activated.
+fn systemd_listener() -> anyhow::Result<Option<Listener>> {
+    let var = |name| {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+    };
+    // the variables are meant for this process, not for children that inherit them
+    if var("LISTEN_PID") != Some(process::id()) {
+        return Ok(None);
+    }
+    match var("LISTEN_FDS") {
+        None | Some(0) => return Ok(None),
+        Some(1) => {}
+        Some(fds) => warn!("systemd passed {fds} sockets, only the first is used"),
+    }
+    info!("using the socket passed by systemd");
+
+    // SAFETY: systemd passed this socket to us, nothing else in the process owns it
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    // only has an address if it really is a unix socket
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(Some(Listener::Unix(UnixListener::from_std(unix)?, None)));
+    }
+    let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+    tcp.set_nonblocking(true)?;
+    Ok(Some(Listener::Tcp(TcpListener::from_std(tcp)?)))
+}
+
+/// Removes the socket file left behind by a server that crashed.
+///
+/// Fails when another server is still listening on it, or the path is not a socket.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context(format!("could not check {path:?}")),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{path:?} exists and is not a socket");
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!("another server is listening on {path:?}");
+    }
+    warn!("removing stale socket {path:?}");
+    remove_file(path)?;
+    Ok(())
+}
+
+pub async fn bind(config: &Config) -> anyhow::Result<Listener> {
+    if let Some(listener) = systemd_listener()? {
+        return Ok(listener);
+    }
+    match config.listen() {
+        Listen::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener =
+                UnixListener::bind(&path).with_context(|| format!("could not bind {path:?}"))?;
+            set_permissions(&path, Permissions::from_mode(0o777))?;
+            Ok(Listener::Unix(listener, Some(path)))
+        }
+        Listen::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+    }
+}
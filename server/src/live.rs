@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        WebSocketUpgrade,
+    },
+    response::Response,
+};
+use jotihunt_shared::Traccar;
+use sled::{Db, IVec, Tree};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    auth::Session,
+    metrics::Metrics,
+    shutdown::{Shutdown, Stopping},
+    AppState,
+};
+
+/// The longest track that can be requested at once, the jotihunt takes a weekend.
+pub const MAX_TRACK: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// Positions reported by the traccar clients of the hunters.
+///
+/// Every report is stored in the `positions` tree under the device id and the time of
+/// the measurement, the newest report of every device also in `positions.latest`.
+pub struct Live {
+    positions: Tree,
+    latest: Tree,
+    send: broadcast::Sender<Traccar>,
+}
+
+fn position_key(device: &str, time: u64) -> Vec<u8> {
+    // the zero byte keeps the positions of a device apart from devices with a longer id
+    let mut key = device.as_bytes().to_vec();
+    key.push(0);
+    key.extend(time.to_be_bytes());
+    key
+}
+
+impl Live {
+    pub fn open(db: &Db) -> sled::Result<Self> {
+        Ok(Self {
+            positions: db.open_tree("positions")?,
+            latest: db.open_tree("positions.latest")?,
+            send: broadcast::channel(16).0,
+        })
+    }
+
+    /// Stores the position and sends it to all connected clients if it is the newest of the device.
+    pub fn report(&self, traccar: Traccar) -> anyhow::Result<()> {
+        let value = postcard::to_allocvec(&traccar)?;
+        self.positions
+            .insert(position_key(&traccar.id, traccar.time), &*value)?;
+
+        // batched reports can contain older positions, those should not replace the latest
+        let mut newest = false;
+        self.latest.fetch_and_update(&traccar.id, |old| {
+            let old_time = old
+                .and_then(|old| postcard::from_bytes::<Traccar>(old).ok())
+                .map(|old| old.time);
+            newest = old_time.is_none_or(|time| time <= traccar.time);
+            match newest {
+                true => Some(IVec::from(&*value)),
+                false => old.map(IVec::from),
+            }
+        })?;
+        if newest {
+            let _ = self.send.send(traccar);
+        }
+        Ok(())
+    }
+
+    /// The newest position of every device.
+    pub fn latest(&self) -> Vec<Traccar> {
+        self.latest
+            .iter()
+            .flatten()
+            .filter_map(|(_, value)| postcard::from_bytes(&value).ok())
+            .collect()
+    }
+
+    /// The positions of a device measured between `from` and `to` (inclusive), oldest first.
+    pub fn track(&self, device: &str, from: u64, to: u64) -> Vec<Traccar> {
+        self.positions
+            .range(position_key(device, from)..=position_key(device, to))
+            .flatten()
+            .filter_map(|(_, value)| postcard::from_bytes(&value).ok())
+            .collect()
+    }
+}
+
+async fn send_positions(
+    stream: &mut WebSocket,
+    positions: Vec<Traccar>,
+) -> Result<(), axum::Error> {
+    for traccar in positions {
+        let bin = postcard::to_stdvec(&traccar).unwrap();
+        stream
+            .send(Message::Binary(axum::body::Bytes::from_owner(bin)))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Accepts the websocket for the live positions, logging in a span with the user under the
+/// request.
+pub fn upgrade(req: WebSocketUpgrade, state: AppState, session: Session) -> Response {
+    let span = info_span!("connection", tree = "live", user = %session.user);
+    req.on_upgrade(move |ws| {
+        async move { live_ws(ws, &state.live, &state.metrics, &state.shutdown).await }
+            .instrument(span)
+    })
+}
+
+/// Sends the newest position of every device, followed by all new reports.
+pub async fn live_ws(stream: WebSocket, live: &Live, metrics: &Metrics, shutdown: &Shutdown) {
+    let _connection = metrics.connected("live");
+    info!("client connected");
+    send_live(stream, live, metrics, shutdown.subscribe()).await;
+    info!("client disconnected");
+}
+
+async fn send_live(mut stream: WebSocket, live: &Live, metrics: &Metrics, mut stopping: Stopping) {
+    // subscribe first, a report that arrives in between is sent twice instead of never
+    let mut receiver = live.send.subscribe();
+    if send_positions(&mut stream, live.latest()).await.is_err() {
+        return;
+    }
+    loop {
+        let received = tokio::select! {
+            received = receiver.recv() => received,
+            () = stopping.wait() => {
+                let _ = stream
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "server is stopping".into(),
+                    })))
+                    .await;
+                break;
+            }
+        };
+        let positions = match received {
+            Ok(traccar) => vec![traccar],
+            Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("live client skipped {skipped} reports, sending latest positions");
+                metrics.live_skipped(skipped);
+                live.latest()
+            }
+        };
+        if send_positions(&mut stream, positions).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Logging with `tracing`, every request and websocket connection gets its own span.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, MatchedPath},
+    http::Request,
+};
+use std::net::SocketAddr;
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Config, LogFormat};
+
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.log_level)
+            .with_context(|| format!("invalid log level {:?}", config.log_level))?,
+    };
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Human => logs.init(),
+        LogFormat::Json => logs.json().with_span_list(true).init(),
+    }
+    Ok(())
+}
+
+/// The address of the client, as told by the reverse proxy when there is one.
+fn peer(request: &Request<Body>) -> String {
+    let forwarded = ["x-forwarded-for", "x-real-ip"]
+        .into_iter()
+        .find_map(|name| request.headers().get(name)?.to_str().ok());
+    if let Some(forwarded) = forwarded {
+        // the first address is the client, the rest are proxies
+        return forwarded
+            .split(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_owned();
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+/// The span of a request, with the route instead of the path so the session key is not logged.
+pub fn request_span(request: &Request<Body>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("-", MatchedPath::as_str);
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or("-");
+    info_span!(
+        "request",
+        id,
+        method = %request.method(),
+        route,
+        peer = peer(request),
+    )
+}
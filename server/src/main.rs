@@ -1,247 +1,60 @@
-mod article;
-mod geojson;
-mod status;
+use std::{fs::remove_file, net::SocketAddr};
 
-use std::{
-    fs::{read_to_string, set_permissions, File, Permissions},
-    io::Write,
-    ops::Not,
-    os::unix::fs::PermissionsExt,
+use server::{
+    build_router,
+    config::Config,
+    listen::{self, Listener},
+    logging, metrics_router, shutdown, AppState,
 };
-
-use article::retrieve_articles_loop;
-use async_stream::stream;
-use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        Json, Path, Query, Request, WebSocketUpgrade,
-    },
-    http::StatusCode,
-    middleware::Next,
-    response::IntoResponse,
-    routing::{any, get},
-    RequestExt, Router,
-};
-use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
-use geojson::get_reloading_geojson;
-use jotihunt_shared::{AtomicEdit, Broadcast, Traccar};
-use sled::{Event, Tree};
-
-use status::retrieve_status_loop;
-use tokio::sync::broadcast::{self, error::RecvError};
-use tower_http::{cors::CorsLayer, validate_request::ValidateRequestHeaderLayer};
-use uuid::Uuid;
+use tokio::net::TcpListener;
+use tracing::{error, info};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    if let Ok(mut file) = File::create_new("password") {
-        write!(&mut file, "test").unwrap();
-    }
-    let password = read_to_string("password").unwrap();
-
-    println!("password is: {}", password);
-    let secret = Uuid::new_v4();
-
-    let db = leak(sled::open("joti.db").unwrap());
-    println!("{} items in db", db.scan_prefix([]).count());
-
-    let live = leak(broadcast::channel(16).0);
-
-    let geojson = get_reloading_geojson().await;
-    let fox_list = retrieve_status_loop(db).await;
-    tokio::spawn(retrieve_articles_loop(db));
-
-    let router = Router::new()
-        .route(
-            "/secret",
-            get(move || async move { secret.to_string() })
-                .route_layer(CorsLayer::very_permissive())
-                .layer(ValidateRequestHeaderLayer::basic("", &password)),
-        )
-        .nest(
-            "/{key}",
-            Router::new()
-                .route(
-                    "/locations",
-                    get(move |req: WebSocketUpgrade| async move {
-                        req.on_upgrade(|ws| accept_and_log(ws, db))
-                    }),
-                )
-                .route(
-                    "/status",
-                    get(move |req: WebSocketUpgrade| async move {
-                        req.on_upgrade(|ws| async {
-                            let tree = db.open_tree("status").unwrap();
-                            accept_and_log(ws, &tree).await
-                        })
-                    }),
-                )
-                .route(
-                    "/articles",
-                    get(move |req: WebSocketUpgrade| async move {
-                        req.on_upgrade(|ws| async {
-                            let tree = db.open_tree("articles").unwrap();
-                            accept_and_log(ws, &tree).await
-                        })
-                    }),
-                )
-                .route(
-                    "/live",
-                    get(move |req: WebSocketUpgrade| async move {
-                        req.on_upgrade(|ws| live_ws(ws, live.subscribe()))
-                    }),
-                )
-                .route_layer(axum::middleware::from_fn(
-                    move |Path(key): Path<Uuid>, request: Request, next: Next| async move {
-                        if key != secret {
-                            return StatusCode::UNAUTHORIZED.into_response();
-                        }
-                        next.run(request).await
-                    },
-                )),
-        )
-        .route(
-            "/traccar",
-            any(|mut req: Request| async {
-                let traccar = match req.extract_parts::<Query<Traccar>>().await {
-                    Ok(t) => t.0,
-                    Err(_e) => {
-                        let json: Json<NewTraccar> = match req.extract().await {
-                            Ok(v) => v,
-                            Err(e) => return e.into_response(),
-                        };
-                        Traccar {
-                            id: json.0.device_id,
-                            lat: json.0.location.coords.latitude.to_string(),
-                            lon: json.0.location.coords.longitude.to_string(),
-                        }
-                    }
-                };
-                let _ = live.send(traccar);
-                StatusCode::OK.into_response()
-            }),
-        )
-        .route(
-            "/deelnemers.geojson",
-            get(move || async move { geojson.load().as_ref().clone() })
-                .route_layer(CorsLayer::very_permissive()),
-        )
-        .route(
-            "/fox_list.json",
-            get(move || async move { fox_list.load().as_ref().clone() })
-                .route_layer(CorsLayer::very_permissive()),
-        );
-
-    let path = "/run/jotihunt/socket";
-    let listener = tokio::net::UnixListener::bind(path)?;
-    set_permissions(path, Permissions::from_mode(0o777))?;
-    axum::serve(listener, router).await?;
-
-    Ok(())
-}
-
-#[derive(serde::Deserialize)]
-struct NewTraccar {
-    device_id: String,
-    location: NewLocation,
-}
-
-#[derive(serde::Deserialize)]
-struct NewLocation {
-    coords: NewCoords,
-}
-
-#[derive(serde::Deserialize)]
-struct NewCoords {
-    latitude: f64,
-    longitude: f64,
-}
-
-async fn accept_and_log(stream: WebSocket, db: &Tree) {
-    match accept_connection(stream, db).await {
-        Ok(()) => {}
-        Err(e) => {
-            println!("error on connection: {}", e)
-        }
-    }
-}
-
-async fn accept_connection(stream: WebSocket, db: &Tree) -> anyhow::Result<()> {
-    println!("client connected");
-
-    let (write, read) = stream.split();
-    let receive_edits = read
-        .map_err(anyhow::Error::from)
-        .try_filter_map(|msg| match msg {
-            Message::Binary(b) => future::ok(Some(b)),
-            _ => future::ok(None),
-        })
-        .try_for_each(|bin| async move {
-            let edit: AtomicEdit = postcard::from_bytes(&bin)?;
-            let new = edit.new.is_empty().not().then_some(edit.new);
-            let old = edit.old.is_empty().not().then_some(edit.old);
-            // println!("received(bin): {:?}", bin);
-            // println!("received: {:?}, {:?}, {:?}", edit.key, old, new);
-
-            let _ = db.compare_and_swap(edit.key, old, new).unwrap();
-            Ok(())
+    let config = Config::load()?;
+    logging::init(&config)?;
+
+    let state = AppState::open(config)?;
+    state.start();
+    let router = build_router(state.clone());
+
+    if let Some(addr) = state.config().metrics_listen {
+        let listener = TcpListener::bind(addr).await?;
+        let metrics = metrics_router(state.clone());
+        info!("serving metrics on {addr}");
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, metrics).await {
+                error!("error serving metrics: {err}");
+            }
         });
+    }
 
-    let send_edits = stream! {
-        let mut subscriber = db.watch_prefix([]);
-        for pair in db {
-            yield pair.unwrap();
+    let stop = {
+        let state = state.clone();
+        async move {
+            shutdown::signal_received().await;
+            state.stop();
         }
-        while let Some(event) = (&mut subscriber).await {
-            match event {
-                Event::Insert { key, value } => {
-                    yield (key, value)
-                }
-                Event::Remove { key } => {
-                    yield (key, Default::default())
-                }
+    };
+    match listen::bind(state.config()).await? {
+        Listener::Unix(listener, path) => {
+            axum::serve(listener, router)
+                .with_graceful_shutdown(stop)
+                .await?;
+            if let Some(path) = path {
+                remove_file(path)?;
             }
         }
+        Listener::Tcp(listener) => {
+            let router = router.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, router)
+                .with_graceful_shutdown(stop)
+                .await?;
+        }
     }
-    .map(|(key, value)| {
-        let bin = postcard::to_stdvec(&Broadcast {
-            key: key.as_ref().to_owned(),
-            value: value.as_ref().to_owned(),
-        })
-        .unwrap();
-        // println!("sending: {:?}", bin);
-        Ok(Message::Binary(axum::body::Bytes::from_owner(bin)))
-    })
-    .forward(write);
 
-    pin_mut!(receive_edits, send_edits);
-    future::select(receive_edits, send_edits).await;
-
-    println!("client disconnected");
+    state.close().await?;
+    info!("stopped");
 
     Ok(())
 }
-
-type LiveReceiver = broadcast::Receiver<Traccar>;
-
-async fn live_ws(mut stream: WebSocket, mut live: LiveReceiver) {
-    loop {
-        match live.recv().await {
-            Ok(traccar) => {
-                let bin = postcard::to_stdvec(&traccar).unwrap();
-                let Ok(()) = stream
-                    .send(Message::Binary(axum::body::Bytes::from_owner(bin)))
-                    .await
-                else {
-                    break;
-                };
-            }
-            Err(RecvError::Closed) => break,
-            Err(RecvError::Lagged(_)) => continue,
-        }
-    }
-}
-
-fn leak<T>(val: T) -> &'static T {
-    &*Box::leak(Box::new(val))
-}
@@ -0,0 +1,212 @@
+//! Counters and gauges in the Prometheus text format, served on `/metrics`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use jotihunt_shared::protocol::EditResult;
+
+use crate::health::Health;
+
+/// Writes samples in the Prometheus text format.
+#[derive(Default)]
+pub struct Exposition(String);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Exposition {
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").unwrap();
+        writeln!(self.0, "# TYPE {name} {kind}").unwrap();
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>();
+        match labels.is_empty() {
+            true => writeln!(self.0, "{name} {value}").unwrap(),
+            false => writeln!(self.0, "{name}{{{}}} {value}", labels.join(",")).unwrap(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Timing {
+    count: u64,
+    seconds: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Open websocket connections by tree
+    connections: Mutex<BTreeMap<String, u64>>,
+    /// Edits by tree and result
+    edits: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Requests to the jotihunt api by endpoint and result
+    upstream: Mutex<BTreeMap<(String, &'static str), Timing>>,
+    /// Traccar reports by device and result
+    traccar: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Reports the live clients skipped because they could not keep up
+    live_skipped: AtomicU64,
+}
+
+/// Counts as an open connection until it is dropped.
+pub struct Connection<'a> {
+    metrics: &'a Metrics,
+    tree: String,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        if let Some(open) = self.metrics.connections.lock().unwrap().get_mut(&self.tree) {
+            *open -= 1;
+        }
+    }
+}
+
+impl Metrics {
+    pub fn connected(&self, tree: &str) -> Connection<'_> {
+        *self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(tree.to_owned())
+            .or_default() += 1;
+        Connection {
+            metrics: self,
+            tree: tree.to_owned(),
+        }
+    }
+
+    pub fn edit(&self, tree: &str, result: &EditResult) {
+        let result = match result {
+            EditResult::Applied => "applied",
+            EditResult::Rejected { .. } => "conflict",
+            EditResult::Forbidden => "forbidden",
+        };
+        *self
+            .edits
+            .lock()
+            .unwrap()
+            .entry((tree.to_owned(), result))
+            .or_default() += 1;
+    }
+
+    pub fn upstream(&self, endpoint: &str, success: bool, duration: Duration) {
+        let result = if success { "success" } else { "error" };
+        let mut upstream = self.upstream.lock().unwrap();
+        let timing = upstream.entry((endpoint.to_owned(), result)).or_default();
+        timing.count += 1;
+        timing.seconds += duration.as_secs_f64();
+    }
+
+    /// `device` is only the id of an approved device, anyone can send reports.
+    pub fn traccar(&self, device: Option<&str>, result: &'static str) {
+        let device = device.unwrap_or("unknown").to_owned();
+        *self
+            .traccar
+            .lock()
+            .unwrap()
+            .entry((device, result))
+            .or_default() += 1;
+    }
+
+    pub fn live_skipped(&self, skipped: u64) {
+        self.live_skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, health: &Health) -> String {
+        let mut out = Exposition::default();
+
+        out.family(
+            "jotihunt_connections",
+            "gauge",
+            "Open websocket connections.",
+        );
+        for (tree, open) in self.connections.lock().unwrap().iter() {
+            out.sample("jotihunt_connections", &[("tree", tree)], open);
+        }
+
+        out.family(
+            "jotihunt_edits_total",
+            "counter",
+            "Edits from clients, by whether they were applied.",
+        );
+        for ((tree, result), count) in self.edits.lock().unwrap().iter() {
+            let labels = [("tree", tree.as_str()), ("result", result)];
+            out.sample("jotihunt_edits_total", &labels, count);
+        }
+
+        health.metrics(&mut out);
+
+        out.family(
+            "jotihunt_upstream_request_duration_seconds",
+            "summary",
+            "Requests to the jotihunt api.",
+        );
+        for ((endpoint, result), timing) in self.upstream.lock().unwrap().iter() {
+            let labels = [("endpoint", endpoint.as_str()), ("result", result)];
+            let name = "jotihunt_upstream_request_duration_seconds";
+            out.sample(&format!("{name}_sum"), &labels, timing.seconds);
+            out.sample(&format!("{name}_count"), &labels, timing.count);
+        }
+
+        out.family(
+            "jotihunt_traccar_reports_total",
+            "counter",
+            "Reports of traccar clients, the device is unknown until it is approved.",
+        );
+        for ((device, result), count) in self.traccar.lock().unwrap().iter() {
+            let labels = [("device", device.as_str()), ("result", result)];
+            out.sample("jotihunt_traccar_reports_total", &labels, count);
+        }
+
+        out.family(
+            "jotihunt_live_skipped_reports_total",
+            "counter",
+            "Traccar reports live clients skipped because they fell behind.",
+        );
+        let skipped = self.live_skipped.load(Ordering::Relaxed);
+        out.sample("jotihunt_live_skipped_reports_total", &[], skipped);
+
+        out.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_connections_and_edits() {
+        let metrics = Metrics::default();
+        let first = metrics.connected("locations");
+        let _second = metrics.connected("locations");
+        drop(first);
+        metrics.edit("locations", &EditResult::Applied);
+        metrics.edit("locations", &EditResult::Forbidden);
+        metrics.traccar(Some("phone \"1\""), "stored");
+
+        let text = metrics.render(&Health::default());
+        assert!(text.contains("jotihunt_connections{tree=\"locations\"} 1\n"));
+        assert!(text.contains("jotihunt_edits_total{tree=\"locations\",result=\"applied\"} 1\n"));
+        assert!(text.contains("jotihunt_edits_total{tree=\"locations\",result=\"forbidden\"} 1\n"));
+        assert!(text.contains(
+            "jotihunt_traccar_reports_total{device=\"phone \\\"1\\\"\",result=\"stored\"} 1\n"
+        ));
+        assert!(text.contains("jotihunt_live_skipped_reports_total 0\n"));
+    }
+}
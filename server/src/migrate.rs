@@ -0,0 +1,89 @@
+use jotihunt_shared::{coordinate::Coordinate, domain::Fox, AuditEntry};
+use serde::Deserialize;
+use sled::Db;
+use tracing::{info, warn};
+
+use crate::synced::SyncedTree;
+
+/// Brings the database up to date with the current layout.
+pub fn run(db: &Db) -> anyhow::Result<()> {
+    locations_tree(db)?;
+    once(db, "fox_coordinates", fox_coordinates)?;
+    Ok(())
+}
+
+/// Runs a migration that can not tell by itself whether it already ran.
+fn once(db: &Db, name: &str, migration: fn(&Db) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    let done = db.open_tree("migrations")?;
+    if done.contains_key(name)? {
+        return Ok(());
+    }
+    migration(db)?;
+    done.insert(name, &[])?;
+    done.flush()?;
+    Ok(())
+}
+
+/// Locations used to be stored in the default tree, they now have their own tree.
+fn locations_tree(db: &Db) -> sled::Result<()> {
+    let locations = db.open_tree("locations")?;
+    if db.is_empty() || !locations.is_empty() {
+        return Ok(());
+    }
+    info!("moving {} locations to their own tree", db.len());
+    for pair in db.iter() {
+        let (key, value) = pair?;
+        locations.insert(key, value)?;
+    }
+    locations.flush()?;
+    db.clear()?;
+    Ok(())
+}
+
+/// How a [Fox] was stored before it had a [Coordinate].
+#[derive(Deserialize)]
+struct OldFox {
+    latitude: String,
+    longitude: String,
+}
+
+fn convert_fox(value: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if value.is_empty() {
+        return Ok(vec![]);
+    }
+    let old: OldFox = postcard::from_bytes(value)?;
+    let fox = Fox {
+        coordinate: Coordinate::from_input(&old.latitude, &old.longitude),
+    };
+    Ok(postcard::to_allocvec(&fox)?)
+}
+
+/// Foxes used to store the coordinate as two strings, the audit log has them too.
+fn fox_coordinates(db: &Db) -> anyhow::Result<()> {
+    let locations = SyncedTree::open(db, "locations")?;
+    let (_, entries) = locations.snapshot()?;
+    info!("converting {} locations to coordinates", entries.len());
+    for (key, value) in entries {
+        match convert_fox(&value) {
+            Ok(value) => locations.insert(&key, &value)?,
+            Err(err) => warn!("could not convert location {key:?}: {err}"),
+        }
+    }
+
+    let audit = SyncedTree::open(db, "audit")?;
+    let (_, entries) = audit.snapshot()?;
+    for (key, value) in entries {
+        let Ok(mut entry) = postcard::from_bytes::<AuditEntry>(&value) else {
+            continue;
+        };
+        match (convert_fox(&entry.old), convert_fox(&entry.new)) {
+            (Ok(old), Ok(new)) => {
+                entry.old = old;
+                entry.new = new;
+                audit.insert(&key, &postcard::to_allocvec(&entry)?)?;
+            }
+            _ => warn!("could not convert audit entry {key:?}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Stopping the server on SIGTERM or SIGINT, after the websocket clients are told to go away.
+
+use std::time::Duration;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::timeout,
+};
+use tracing::info;
+
+/// How long the server waits for the websockets to close before it stops anyway.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Shutdown {
+    stop: watch::Sender<bool>,
+}
+
+/// Held by a connection, the server waits for it to be dropped before it stops.
+pub struct Stopping(watch::Receiver<bool>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stop: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn subscribe(&self) -> Stopping {
+        Stopping(self.stop.subscribe())
+    }
+
+    pub fn trigger(&self) {
+        self.stop.send_replace(true);
+    }
+
+    /// Waits until every [Stopping] is dropped, false if that took longer than `limit`.
+    pub async fn closed(&self, limit: Duration) -> bool {
+        timeout(limit, self.stop.closed()).await.is_ok()
+    }
+}
+
+impl Stopping {
+    /// Resolves when the server is stopping.
+    pub async fn wait(&mut self) {
+        let _ = self.0.wait_for(|stop| *stop).await;
+    }
+}
+
+/// Waits for SIGTERM or SIGINT.
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM, stopping"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, stopping"),
+    }
+}
@@ -0,0 +1,136 @@
+//! Everything the handlers and pollers share, opened once per database.
+
+use std::{ops::Deref, sync::Arc};
+
+use arc_swap::ArcSwap;
+use tracing::{info, warn};
+
+use crate::{
+    areas::{self, check_locations_loop, Areas},
+    article::retrieve_articles_loop,
+    audit::Audit,
+    auth::Auth,
+    config::Config,
+    devices::Devices,
+    geojson::{get_reloading_geojson, Participants},
+    health::Health,
+    live::Live,
+    metrics::Metrics,
+    migrate,
+    shutdown::{Shutdown, CLOSE_TIMEOUT},
+    status::retrieve_status_loop,
+    synced::SyncedTree,
+    upstream::Upstream,
+};
+
+/// Cheap to clone, all clones share the same database and pollers.
+#[derive(Clone)]
+pub struct AppState(Arc<Shared>);
+
+/// The fields of [AppState].
+pub struct Shared {
+    pub(crate) config: Config,
+    pub(crate) db: sled::Db,
+
+    pub(crate) locations: SyncedTree,
+    pub(crate) status: SyncedTree,
+    pub(crate) articles: SyncedTree,
+    pub(crate) registry: SyncedTree,
+    pub(crate) checks: SyncedTree,
+    pub(crate) participants: SyncedTree,
+    pub(crate) fox_list_tree: SyncedTree,
+
+    pub(crate) auth: Auth,
+    pub(crate) audit: Audit,
+    pub(crate) live: Live,
+    pub(crate) devices: Devices,
+    pub(crate) areas: Areas,
+
+    pub(crate) upstream: Upstream,
+    pub(crate) health: Health,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) shutdown: Shutdown,
+
+    /// The participants for `/deelnemers.geojson`
+    pub(crate) geojson: ArcSwap<Participants>,
+    /// The names of the areas for `/fox_list.json`
+    pub(crate) fox_list: ArcSwap<String>,
+}
+
+impl Deref for AppState {
+    type Target = Shared;
+
+    fn deref(&self) -> &Shared {
+        &self.0
+    }
+}
+
+impl AppState {
+    /// Opens the database and migrates it, the pollers only run after [AppState::start].
+    pub fn open(config: Config) -> anyhow::Result<Self> {
+        let db = sled::open(&config.db)?;
+        info!("{} items in db", db.scan_prefix([]).count());
+        migrate::run(&db)?;
+
+        let auth = Auth::open(&db)?;
+        auth.bootstrap(&config.password_file)?;
+
+        let areas = Areas::new(match &config.areas_file {
+            Some(path) => areas::load(path)?,
+            None => vec![],
+        });
+        let metrics = Arc::new(Metrics::default());
+
+        Ok(Self(Arc::new(Shared {
+            locations: SyncedTree::open(&db, "locations")?,
+            status: SyncedTree::open(&db, "status")?,
+            articles: SyncedTree::open(&db, "articles")?,
+            registry: SyncedTree::open(&db, "registry")?,
+            checks: SyncedTree::open(&db, "checks")?,
+            participants: SyncedTree::open(&db, "participants")?,
+            fox_list_tree: SyncedTree::open(&db, "fox_list")?,
+            auth,
+            audit: Audit::open(&db)?,
+            live: Live::open(&db)?,
+            devices: Devices::open(&db)?,
+            areas,
+            upstream: Upstream::new(&config, &db, metrics.clone())?,
+            health: Health::default(),
+            metrics,
+            shutdown: Shutdown::default(),
+            geojson: ArcSwap::from_pointee(Participants::default()),
+            fox_list: ArcSwap::from_pointee("[]".to_owned()),
+            config,
+            db,
+        })))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Starts from the cached api responses, and starts the pollers and checks in the background.
+    pub fn start(&self) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            check_locations_loop(&state.locations, &state.checks, &state.areas).await
+        });
+        get_reloading_geojson(self);
+        retrieve_status_loop(self);
+        tokio::spawn(retrieve_articles_loop(self.clone()));
+    }
+
+    /// Tells the websocket clients the server is stopping.
+    pub fn stop(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Waits for the websockets to close after [AppState::stop], and flushes the database.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        if !self.shutdown.closed(CLOSE_TIMEOUT).await {
+            warn!("not all websockets closed in time");
+        }
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
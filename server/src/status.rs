@@ -1,11 +1,18 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::Arc};
 
-use arc_swap::ArcSwap;
 use serde::Deserialize;
-use sled::Db;
 use tokio::time::sleep;
+use tracing::{debug, error, info_span, Instrument};
 
-use crate::leak;
+use crate::{
+    areas::{self, Geometry},
+    synced::SyncedTree,
+    upstream::RETRY_INTERVAL,
+    AppState,
+};
+
+/// Name of the poller in the health report.
+const POLLER: &str = "status";
 
 #[derive(Deserialize)]
 struct Areas {
@@ -17,51 +24,91 @@ struct Area {
     name: String,
     status: String,
     updated_at: String,
+    /// The boundary of the area, the api does not always include it
+    #[serde(default)]
+    geometry: Option<Geometry>,
 }
 
-fn update_single_status(tree: &sled::Tree, area: Area) -> Result<(), postcard::Error> {
+fn update_single_status(
+    tree: &SyncedTree,
+    boundaries: &areas::Areas,
+    area: Area,
+) -> anyhow::Result<()> {
+    if let Some(geometry) = area.geometry {
+        boundaries.replace(areas::Area::from_geometry(area.name.clone(), geometry)?);
+    }
     let key = postcard::to_allocvec(&(&area.updated_at, &area.name))?;
     let value = postcard::to_allocvec(&area.status)?;
-    if tree.get(&key).unwrap().as_slice() != Some(&value).as_slice() {
-        let _old = tree.insert(&key, value).unwrap();
+    tree.insert(&key, &value)?;
+    Ok(())
+}
+
+/// Stores the current status of every area in `fox_list`, keyed by the name of the area.
+fn update_fox_list(fox_list: &SyncedTree, areas: &[Area]) -> anyhow::Result<()> {
+    let mut entries = BTreeMap::new();
+    for area in areas {
+        entries.insert(
+            postcard::to_allocvec(&area.name)?,
+            postcard::to_allocvec(&area.status)?,
+        );
     }
+    fox_list.replace_all(entries)?;
     Ok(())
 }
 
-async fn retrieve_status_inner(tree: &sled::Tree) -> Result<String, reqwest::Error> {
-    let res = reqwest::get("https://jotihunt.nl/api/2.0/areas").await?;
-    let areas: Areas = res.json().await?;
-    let mut foxes = vec![];
+/// Stores the status of the areas and the fox list.
+fn handle_areas(state: &AppState, areas: Areas) {
+    let foxes: Vec<_> = areas.data.iter().map(|area| area.name.clone()).collect();
+    if let Err(err) = update_fox_list(&state.fox_list_tree, &areas.data) {
+        error!("error updating fox list: {err}")
+    }
     for area in areas.data {
-        foxes.push(area.name.clone());
-        if let Err(err) = update_single_status(tree, area) {
-            println!("error handling area: {err}")
+        if let Err(err) = update_single_status(&state.status, &state.areas, area) {
+            error!("error handling area: {err}")
         }
     }
-    Ok(serde_json::to_string(&foxes).unwrap())
+    state
+        .fox_list
+        .store(Arc::new(serde_json::to_string(&foxes).unwrap()));
 }
 
-pub async fn retrieve_status_loop(db: &Db) -> &'static ArcSwap<String> {
-    let tree = db.open_tree("status").unwrap();
-    let list = retrieve_status_inner(&tree).await.unwrap();
-    let arc = leak(ArcSwap::new(Arc::new(list)));
-
-    tokio::spawn(async move {
-        loop {
-            // every minute
-            sleep(Duration::from_secs(60)).await;
-
-            println!("reloading status");
-            match retrieve_status_inner(&tree).await {
-                Ok(list) => {
-                    arc.store(Arc::new(list));
+async fn reload_status(state: AppState) {
+    let interval = state.config.status_interval();
+    loop {
+        let failed = async {
+            debug!("reloading status");
+            match state.upstream.get_and_cache("areas").await {
+                Ok(areas) => {
+                    state.health.success(POLLER);
+                    handle_areas(&state, areas);
+                    false
                 }
                 Err(err) => {
-                    println!("error getting status: {err}");
+                    error!("error getting status: {err}");
+                    state.health.error(POLLER, &err);
+                    true
                 }
             }
         }
-    });
+        .instrument(info_span!("poll", poller = POLLER))
+        .await;
+
+        sleep(match failed {
+            true => interval.min(RETRY_INTERVAL),
+            false => interval,
+        })
+        .await;
+    }
+}
 
-    arc
+/// Loads the status from the last good response, and reloads it from the api in the background
+/// every status interval.
+pub fn retrieve_status_loop(state: &AppState) {
+    state
+        .health
+        .register(POLLER, state.config.status_interval());
+    if let Some(areas) = state.upstream.cached("areas") {
+        handle_areas(state, areas);
+    }
+    tokio::spawn(reload_status(state.clone()));
 }
@@ -0,0 +1,588 @@
+use std::{ops::Not, time::Duration};
+
+use async_stream::try_stream;
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        WebSocketUpgrade,
+    },
+    response::Response,
+};
+use futures_util::{future, pin_mut, stream, Stream, StreamExt};
+use jotihunt_shared::{
+    protocol::{ClientMessage, EditResult, Revision, ServerMessage, PROTOCOL_VERSION},
+    AtomicEdit, Role,
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, timeout, Instant},
+};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+
+use crate::{
+    audit::Audit,
+    auth::{Auth, Session},
+    metrics::Metrics,
+    shutdown::{Shutdown, Stopping},
+    synced::{LogEntry, SyncedTree},
+    AppState,
+};
+
+fn encode(msg: &ServerMessage) -> Message {
+    let bin = postcard::to_stdvec(msg).unwrap();
+    Message::Binary(axum::body::Bytes::from_owner(bin))
+}
+
+/// Accepts the websocket on the `tree` of the state, logging in a span with the tree and user
+/// under the request.
+///
+/// Users with at least the `edit_role` can edit, edits are recorded in the audit log if `audit`.
+pub fn upgrade(
+    req: WebSocketUpgrade,
+    state: AppState,
+    tree: fn(&AppState) -> &SyncedTree,
+    session: Session,
+    edit_role: Option<Role>,
+    audit: bool,
+) -> Response {
+    let span = info_span!("connection", tree = tree(&state).name(), user = %session.user);
+    req.on_upgrade(move |ws| {
+        async move {
+            let audit = audit.then_some(&state.audit);
+            let (auth, metrics, shutdown) = (&state.auth, &state.metrics, &state.shutdown);
+            let handshake_timeout = state.config.handshake_timeout();
+            accept_and_log(
+                ws,
+                tree(&state),
+                auth,
+                &session,
+                edit_role,
+                audit,
+                metrics,
+                shutdown,
+                handshake_timeout,
+            )
+            .await
+        }
+        .instrument(span)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn accept_and_log(
+    stream: WebSocket,
+    db: &SyncedTree,
+    auth: &Auth,
+    session: &Session,
+    edit_role: Option<Role>,
+    audit: Option<&Audit>,
+    metrics: &Metrics,
+    shutdown: &Shutdown,
+    handshake_timeout: Duration,
+) {
+    let _connection = metrics.connected(db.name());
+    let stopping = shutdown.subscribe();
+    let connection = accept_connection(
+        stream,
+        db,
+        auth,
+        session,
+        edit_role,
+        audit,
+        metrics,
+        stopping,
+        handshake_timeout,
+    );
+    match connection.await {
+        Ok(()) => {}
+        Err(e) => {
+            error!("error on connection: {e}")
+        }
+    }
+}
+
+/// Waits for the next binary message, `None` if the connection was closed.
+async fn recv_message(stream: &mut WebSocket) -> anyhow::Result<Option<ClientMessage>> {
+    loop {
+        match stream.recv().await {
+            Some(Ok(Message::Binary(bin))) => return Ok(postcard::from_bytes(&bin).ok()),
+            Some(Ok(Message::Close(_))) | None => return Ok(None),
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
+
+/// Waits for the [ClientMessage::Hello] and checks the protocol version.
+///
+/// Sends an error and closes the connection when the client is incompatible.
+/// Returns the revision the client wants to continue from.
+async fn handshake(stream: &mut WebSocket) -> anyhow::Result<Option<Option<Revision>>> {
+    let version = match recv_message(stream).await? {
+        Some(ClientMessage::Hello { version }) => Some(version),
+        _ => None,
+    };
+
+    if version != Some(PROTOCOL_VERSION) {
+        warn!("client has incompatible protocol version {version:?}");
+        let message = "De server is bijgewerkt: ververs de pagina!".to_owned();
+        stream
+            .send(encode(&ServerMessage::Error { message }))
+            .await?;
+        stream
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::PROTOCOL,
+                reason: "incompatible protocol version".into(),
+            })))
+            .await?;
+        return Ok(None);
+    }
+
+    stream
+        .send(encode(&ServerMessage::Hello {
+            version: PROTOCOL_VERSION,
+        }))
+        .await?;
+
+    match recv_message(stream).await? {
+        Some(ClientMessage::Sync { since }) => Ok(Some(since)),
+        _ => Ok(None),
+    }
+}
+
+fn change_message(revision: u64, entry: LogEntry) -> ServerMessage {
+    if entry.value.is_empty() {
+        ServerMessage::Remove {
+            revision,
+            key: entry.key,
+        }
+    } else {
+        ServerMessage::Insert {
+            revision,
+            key: entry.key,
+            value: entry.value,
+        }
+    }
+}
+
+/// A snapshot of the whole tree.
+fn snapshot(db: &SyncedTree) -> sled::Result<(u64, ServerMessage)> {
+    let (revision, entries) = db.snapshot()?;
+    let log = db.log_id();
+    let revision = Revision { log, revision };
+    Ok((
+        revision.revision,
+        ServerMessage::Snapshot { revision, entries },
+    ))
+}
+
+/// The changes that bring a client from `since` to the current state, and all later changes.
+///
+/// When the changes after `since` are not available the client gets a snapshot instead, also
+/// when the log can not be read later on. Every change after that is sent exactly once, in
+/// order of revision. The stream ends after an error.
+fn tree_messages(
+    db: &SyncedTree,
+    since: Option<Revision>,
+) -> impl Stream<Item = sled::Result<ServerMessage>> + '_ {
+    try_stream! {
+        // subscribe first, so no change is missed
+        let mut subscriber = db.watch_log();
+
+        let missed = match since.filter(|since| since.log == db.log_id()) {
+            Some(since) => db.changes_since(since.revision)?,
+            None => None,
+        };
+        let mut last = match missed {
+            Some(missed) => {
+                let mut last = since.unwrap().revision;
+                debug!("client resumes from {last} with {} changes", missed.len());
+                for (revision, entry) in missed {
+                    last = revision;
+                    yield change_message(revision, entry);
+                }
+                last
+            }
+            None => {
+                let (revision, snapshot) = snapshot(db)?;
+                yield snapshot;
+                revision
+            }
+        };
+
+        // the changes are read from the log, to send them in order
+        while subscriber.changed().await.is_ok() {
+            match db.changes_since(last)? {
+                Some(changes) => {
+                    for (revision, entry) in changes {
+                        last = revision;
+                        yield change_message(revision, entry);
+                    }
+                }
+                None => {
+                    warn!("the log is incomplete after {last}, sending a snapshot");
+                    let (revision, snapshot) = snapshot(db)?;
+                    last = revision;
+                    yield snapshot;
+                }
+            }
+        }
+    }
+}
+
+/// Applies an edit if allowed and returns the result for the client.
+fn apply_edit(
+    db: &SyncedTree,
+    session: &Session,
+    can_edit: bool,
+    audit: Option<&Audit>,
+    edit: AtomicEdit,
+) -> sled::Result<EditResult> {
+    if !can_edit {
+        info!("rejecting edit");
+        return Ok(EditResult::Forbidden);
+    }
+    let new = edit.new.is_empty().not().then_some(&*edit.new);
+    let old = edit.old.is_empty().not().then_some(&*edit.old);
+    trace!(key = ?edit.key, ?old, ?new, "received edit");
+
+    let res = db.compare_and_swap(&edit.key, old, new)?;
+    if let Some(audit) = audit {
+        if let Err(err) = audit.record(&session.user, &edit, res.is_ok()) {
+            error!("error writing audit log: {err}");
+        }
+    }
+    Ok(match res {
+        Ok(()) => EditResult::Applied,
+        Err(err) => EditResult::Rejected {
+            current: err.current.unwrap_or_default().as_ref().to_owned(),
+        },
+    })
+}
+
+/// Sends the tree and all changes to the client, and applies edits from the client if the user
+/// has at least the `edit_role`.
+///
+/// The session is looked up again for every edit, the connection is closed once it has ended.
+/// Edits are recorded in the `audit` log when given. Clients that do not finish the handshake
+/// within `handshake_timeout` are disconnected.
+#[allow(clippy::too_many_arguments)]
+async fn accept_connection(
+    mut stream: WebSocket,
+    db: &SyncedTree,
+    auth: &Auth,
+    session: &Session,
+    edit_role: Option<Role>,
+    audit: Option<&Audit>,
+    metrics: &Metrics,
+    mut stopping: Stopping,
+    handshake_timeout: Duration,
+) -> anyhow::Result<()> {
+    info!("client connected");
+
+    let since = tokio::select! {
+        since = timeout(handshake_timeout, handshake(&mut stream)) => since,
+        // the client did not get anything yet, it simply reconnects
+        () = stopping.wait() => Ok(Ok(None)),
+    };
+    let since = match since {
+        Ok(since) => since?,
+        Err(_) => {
+            warn!("client did not finish the handshake in time");
+            stream
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "handshake timed out".into(),
+                })))
+                .await?;
+            None
+        }
+    };
+    let Some(since) = since else {
+        return Ok(());
+    };
+
+    // messages for the client that are not changes to the tree
+    let (reply_send, mut reply_read) = mpsc::unbounded_channel();
+    let reply_send = &reply_send;
+
+    let (write, mut read) = stream.split();
+    let receive_edits = async move {
+        while let Some(msg) = read.next().await {
+            let bin = match msg? {
+                Message::Binary(bin) => bin,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let reply = match postcard::from_bytes(&bin) {
+                Ok(ClientMessage::Edit { id, edit }) => {
+                    // the user may have logged out or been changed since connecting
+                    let current = auth.session(session.token);
+                    let can_edit = current
+                        .as_ref()
+                        .is_some_and(|current| edit_role.is_some_and(|role| current.role >= role));
+                    let result = match apply_edit(db, session, can_edit, audit, edit) {
+                        Ok(result) => result,
+                        Err(err) => {
+                            // the client keeps the edit queued and retries it after reconnecting
+                            error!("error applying edit: {err}");
+                            let _ = reply_send.send(Message::Close(Some(CloseFrame {
+                                code: close_code::ERROR,
+                                reason: "could not save the edit".into(),
+                            })));
+                            continue;
+                        }
+                    };
+                    metrics.edit(db.name(), &result);
+                    let _ = reply_send.send(encode(&ServerMessage::Ack { id, result }));
+                    if current.is_none() {
+                        info!("session ended, closing");
+                        let _ = reply_send.send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "session ended".into(),
+                        })));
+                    }
+                    None
+                }
+                Ok(ClientMessage::Ping) => Some(ServerMessage::Pong),
+                Ok(ClientMessage::Pong) => None,
+                Ok(ClientMessage::Hello { .. } | ClientMessage::Sync { .. }) => {
+                    Some(ServerMessage::Error {
+                        message: "unexpected handshake".to_owned(),
+                    })
+                }
+                Err(err) => Some(ServerMessage::Error {
+                    message: format!("could not read message: {err}"),
+                }),
+            };
+            if let Some(reply) = reply {
+                let _ = reply_send.send(encode(&reply));
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    // the client would silently miss changes, closing makes it reconnect and resync
+    let changes = tree_messages(db, since).map(|msg| match msg {
+        Ok(msg) => encode(&msg),
+        Err(err) => {
+            error!("error reading the tree: {err}");
+            Message::Close(Some(CloseFrame {
+                code: close_code::ERROR,
+                reason: "could not read the data".into(),
+            }))
+        }
+    });
+
+    // keeps the connection alive through proxies
+    let period = Duration::from_secs(30);
+    let pings = stream::unfold(
+        interval_at(Instant::now() + period, period),
+        |mut i| async {
+            i.tick().await;
+            Some((ServerMessage::Ping, i))
+        },
+    );
+
+    let replies = stream::poll_fn(|cx| reply_read.poll_recv(cx));
+    // the client reconnects when the server is back
+    let going_away = stream::once(future::ready(Ok(Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "server is stopping".into(),
+    })))));
+    let send_edits = stream::select(
+        changes,
+        stream::select(replies, pings.map(|msg| encode(&msg))),
+    )
+    .map(Ok)
+    .take_until(stopping.wait())
+    .chain(going_away)
+    .forward(write);
+
+    pin_mut!(receive_edits, send_edits);
+    if let future::Either::Left((Err(err), _)) = future::select(receive_edits, send_edits).await {
+        return Err(err);
+    }
+
+    info!("client disconnected");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use futures_util::SinkExt;
+    use tokio::{sync::watch, time::timeout};
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+
+    const KEYS: u8 = 20;
+
+    /// Inserts and removes keys, so the tree changes while clients connect.
+    fn hammer(tree: &SyncedTree, seed: u64) {
+        let mut state = seed;
+        for _ in 0..500 {
+            // xorshift, good enough to mix up the writes
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = [(state % KEYS as u64) as u8];
+            match tree.get(key).unwrap() {
+                Some(current) if state.is_multiple_of(3) => {
+                    let _ = tree.compare_and_swap(&key, Some(&current), None).unwrap();
+                }
+                _ => tree.insert(&key, &state.to_be_bytes()).unwrap(),
+            }
+        }
+    }
+
+    async fn send(ws: &mut WsClient, msg: ClientMessage) {
+        let bin = postcard::to_stdvec(&msg).unwrap();
+        ws.send(tungstenite::Message::Binary(bin.into()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(ws: &mut WsClient) -> ServerMessage {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                tungstenite::Message::Binary(bin) => return postcard::from_bytes(&bin).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    type WsClient = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Connects and follows the tree until `done` has the final revision, returns what the client saw.
+    async fn follow(
+        url: String,
+        mut done: watch::Receiver<Option<u64>>,
+    ) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        send(
+            &mut ws,
+            ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )
+        .await;
+        assert_eq!(
+            recv(&mut ws).await,
+            ServerMessage::Hello {
+                version: PROTOCOL_VERSION
+            }
+        );
+        send(&mut ws, ClientMessage::Sync { since: None }).await;
+
+        let ServerMessage::Snapshot { revision, entries } = recv(&mut ws).await else {
+            panic!("expected a snapshot first");
+        };
+        let mut last = revision.revision;
+        let mut data: BTreeMap<_, _> = entries.into_iter().collect();
+
+        loop {
+            if done.borrow_and_update().is_some_and(|done| done == last) {
+                return data;
+            }
+            let msg = tokio::select! {
+                msg = recv(&mut ws) => msg,
+                _ = done.changed() => continue,
+            };
+            match msg {
+                ServerMessage::Insert {
+                    revision,
+                    key,
+                    value,
+                } => {
+                    assert_eq!(revision, last + 1, "changes are sent once and in order");
+                    last = revision;
+                    data.insert(key, value);
+                }
+                ServerMessage::Remove { revision, key } => {
+                    assert_eq!(revision, last + 1, "changes are sent once and in order");
+                    last = revision;
+                    assert!(
+                        data.remove(&key).is_some(),
+                        "removed a key the client never got"
+                    );
+                }
+                ServerMessage::Ping => {}
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn snapshot_then_stream_under_concurrent_writes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree: &'static SyncedTree = Box::leak(Box::new(SyncedTree::open(&db, "test").unwrap()));
+        let auth: &'static Auth = Box::leak(Box::new(Auth::open(&db).unwrap()));
+        let session = Session {
+            token: uuid::Uuid::nil(),
+            user: "test".to_owned(),
+            role: Role::Editor,
+        };
+
+        let router = Router::new().route(
+            "/ws",
+            get(move |req: WebSocketUpgrade| async move {
+                req.on_upgrade(move |ws| async move {
+                    let metrics = Metrics::default();
+                    let shutdown = Shutdown::default();
+                    let handshake_timeout = Duration::from_secs(10);
+                    accept_and_log(
+                        ws,
+                        tree,
+                        auth,
+                        &session,
+                        None,
+                        None,
+                        &metrics,
+                        &shutdown,
+                        handshake_timeout,
+                    )
+                    .await
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let (done_send, done) = watch::channel(None);
+        let writers: Vec<_> = (1..=4)
+            .map(|seed| tokio::task::spawn_blocking(move || hammer(tree, seed * 7919)))
+            .collect();
+        let clients: Vec<_> = (0..16)
+            .map(|i| {
+                let (url, done) = (url.clone(), done.clone());
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(i * 3)).await;
+                    follow(url, done).await
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        let (revision, entries) = tree.snapshot().unwrap();
+        let expected: BTreeMap<_, _> = entries.into_iter().collect();
+        done_send.send(Some(revision)).unwrap();
+
+        for client in clients {
+            let seen = timeout(Duration::from_secs(30), client)
+                .await
+                .expect("client caught up")
+                .unwrap();
+            assert_eq!(seen, expected);
+        }
+    }
+}
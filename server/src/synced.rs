@@ -0,0 +1,271 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+    },
+    CompareAndSwapError, Db, IVec, Tree,
+};
+use tokio::sync::watch;
+
+/// Raw keys and values of a tree.
+pub type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A change to the tree, stored in the log under its revision.
+#[derive(Serialize, Deserialize)]
+pub struct LogEntry {
+    pub key: Vec<u8>,
+    /// Empty when the key was removed
+    pub value: Vec<u8>,
+}
+
+/// A tree that keeps a log of all changes, so clients can catch up after reconnecting.
+///
+/// Every change increases the revision of the tree by one and is stored in the
+/// `{name}.log` tree under the big endian revision.
+/// All writes to the tree have to go through this type, to keep the log complete.
+/// Open every tree only once and share it, so snapshots are consistent with the writes.
+#[derive(Clone)]
+pub struct SyncedTree {
+    name: String,
+    tree: Tree,
+    log: Tree,
+    meta: Tree,
+    log_id: u64,
+    /// See [SyncedTree::append_only]
+    append_only: bool,
+    /// Held shared by writes and exclusively while taking a snapshot.
+    snapshot_lock: Arc<RwLock<()>>,
+    /// Notified after every change, unlike sled subscribers this never blocks the writer.
+    changed: Arc<watch::Sender<()>>,
+}
+
+fn revision_key(name: &str) -> String {
+    format!("{name}/revision")
+}
+
+fn log_id_key(name: &str) -> String {
+    format!("{name}/log_id")
+}
+
+fn read_u64(value: Option<IVec>) -> Option<u64> {
+    Some(u64::from_be_bytes(value?.as_ref().try_into().ok()?))
+}
+
+impl SyncedTree {
+    pub fn open(db: &Db, name: &str) -> sled::Result<Self> {
+        let meta = db.open_tree("sync_meta")?;
+        // a random id for the log, so clients notice when the database was replaced
+        let new_id = uuid::Uuid::new_v4().as_u64_pair().0;
+        let _ =
+            meta.compare_and_swap(log_id_key(name), None::<&[u8]>, Some(&new_id.to_be_bytes()))?;
+        let log_id = read_u64(meta.get(log_id_key(name))?).unwrap();
+        Ok(Self {
+            name: name.to_owned(),
+            tree: db.open_tree(name)?,
+            log: db.open_tree(format!("{name}.log"))?,
+            meta,
+            log_id,
+            append_only: false,
+            snapshot_lock: Arc::default(),
+            changed: Arc::new(watch::Sender::new(())),
+        })
+    }
+
+    /// For trees where keys are only added and never change, like the audit log.
+    ///
+    /// The log then only stores the keys, the values are read from the tree.
+    pub fn append_only(self) -> Self {
+        Self {
+            append_only: true,
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Identifies this log, revisions from a different log are meaningless.
+    pub fn log_id(&self) -> u64 {
+        self.log_id
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, key: impl AsRef<[u8]>) -> sled::Result<Option<IVec>> {
+        self.tree.get(key)
+    }
+
+    pub fn revision(&self) -> sled::Result<u64> {
+        Ok(read_u64(self.meta.get(revision_key(&self.name))?).unwrap_or(0))
+    }
+
+    fn log_change(
+        &self,
+        log: &TransactionalTree,
+        meta: &TransactionalTree,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), ConflictableTransactionError<Option<IVec>>> {
+        let revision = read_u64(meta.get(revision_key(&self.name))?).unwrap_or(0) + 1;
+        meta.insert(revision_key(&self.name).as_bytes(), &revision.to_be_bytes())?;
+        let value = match self.append_only {
+            true => &[],
+            false => value.unwrap_or_default(),
+        };
+        let entry = postcard::to_allocvec(&LogEntry {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+        .unwrap();
+        log.insert(&revision.to_be_bytes(), entry)?;
+        Ok(())
+    }
+
+    /// Sets the key to the value (or removes it when `new` is `None`) if it currently is `old`.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> sled::Result<Result<(), CompareAndSwapError>> {
+        let _guard = self.snapshot_lock.read().unwrap();
+        let res = (&self.tree, &self.log, &self.meta).transaction(|(tree, log, meta)| {
+            let current = tree.get(key)?;
+            if current.as_deref() != old {
+                return Err(ConflictableTransactionError::Abort(current));
+            }
+            if current.as_deref() == new {
+                return Ok(());
+            }
+            match new {
+                Some(new) => tree.insert(key, new)?,
+                None => tree.remove(key)?,
+            };
+            self.log_change(log, meta, key, new)
+        });
+        match res {
+            Ok(()) => {
+                self.changed.send_replace(());
+                Ok(Ok(()))
+            }
+            Err(TransactionError::Abort(current)) => Ok(Err(CompareAndSwapError {
+                current,
+                proposed: new.map(IVec::from),
+            })),
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+
+    /// Sets the key to the value, does nothing if it already has that value.
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> sled::Result<()> {
+        let _guard = self.snapshot_lock.read().unwrap();
+        let res = (&self.tree, &self.log, &self.meta).transaction(|(tree, log, meta)| {
+            if tree.get(key)?.as_deref() == Some(value) {
+                return Ok(());
+            }
+            tree.insert(key, value)?;
+            self.log_change(log, meta, key, Some(value))
+        });
+        match res {
+            Ok(()) => {
+                self.changed.send_replace(());
+                Ok(())
+            }
+            Err(TransactionError::Abort(_)) => Ok(()),
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+
+    /// Removes the key, does nothing if it is not in the tree.
+    pub fn remove(&self, key: &[u8]) -> sled::Result<()> {
+        let _guard = self.snapshot_lock.read().unwrap();
+        let res = (&self.tree, &self.log, &self.meta).transaction(|(tree, log, meta)| {
+            if tree.remove(key)?.is_none() {
+                return Ok(());
+            }
+            self.log_change(log, meta, key, None)
+        });
+        match res {
+            Ok(()) => {
+                self.changed.send_replace(());
+                Ok(())
+            }
+            Err(TransactionError::Abort(_)) => Ok(()),
+            Err(TransactionError::Storage(err)) => Err(err),
+        }
+    }
+
+    /// Makes the tree contain exactly these entries, only changed entries are logged.
+    pub fn replace_all(&self, entries: BTreeMap<Vec<u8>, Vec<u8>>) -> sled::Result<()> {
+        for (key, _) in self.snapshot()?.1 {
+            if !entries.contains_key(&key) {
+                self.remove(&key)?;
+            }
+        }
+        for (key, value) in entries {
+            self.insert(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// The current revision and all entries in the tree at exactly that revision.
+    ///
+    /// Writes wait until the snapshot is taken.
+    pub fn snapshot(&self) -> sled::Result<(u64, Entries)> {
+        let _guard = self.snapshot_lock.write().unwrap();
+        let revision = self.revision()?;
+        let entries = self
+            .tree
+            .iter()
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((key.as_ref().to_owned(), value.as_ref().to_owned()))
+            })
+            .collect::<sled::Result<_>>()?;
+        Ok((revision, entries))
+    }
+
+    /// The entries in the tree, unlike [SyncedTree::snapshot] without a revision, so writes
+    /// do not have to wait.
+    pub fn iter(&self) -> sled::Iter {
+        self.tree.iter()
+    }
+
+    /// Notifies about changes after subscribing, read them with [SyncedTree::changes_since].
+    pub fn watch_log(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// All changes after `revision`, or `None` if they can not be reconstructed.
+    pub fn changes_since(&self, revision: u64) -> sled::Result<Option<Vec<(u64, LogEntry)>>> {
+        if revision > self.revision()? {
+            return Ok(None);
+        }
+        let mut changes = vec![];
+        let mut expected = revision + 1;
+        for pair in self.log.range(expected.to_be_bytes()..) {
+            let (key, value) = pair?;
+            let Some(rev) = read_u64(Some(key)) else {
+                continue;
+            };
+            // the log is incomplete
+            if rev != expected {
+                return Ok(None);
+            }
+            let Ok(mut entry) = postcard::from_bytes::<LogEntry>(&value) else {
+                return Ok(None);
+            };
+            if self.append_only {
+                entry.value = self.tree.get(&entry.key)?.unwrap_or_default().to_vec();
+            }
+            changes.push((rev, entry));
+            expected += 1;
+        }
+        Ok(Some(changes))
+    }
+}
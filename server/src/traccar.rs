@@ -0,0 +1,298 @@
+//! Position reports of the Traccar Client and similar apps.
+//!
+//! Two formats are accepted on `/traccar`:
+//! - the OsmAnd protocol, with the fields as query parameters or as a form body,
+//! - the JSON of background geolocation apps, with a single `location` or an array of them.
+//!
+//! Devices send their token in the path, as `/traccar/{token}`.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use jotihunt_shared::{coordinate::Coordinate, Traccar};
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{debug, error, instrument, warn, Span};
+use uuid::Uuid;
+
+use crate::{devices::Devices, live::Live, metrics::Metrics};
+
+/// Unknown devices are stored by their id, so it can not be arbitrarily long.
+const MAX_ID_LEN: usize = 64;
+
+/// OsmAnd sends the speed in knots.
+const KNOT: f64 = 1852.0 / 3600.0;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Reads unix seconds, unix milliseconds or an RFC 3339 date.
+fn parse_time(time: &str) -> anyhow::Result<u64> {
+    if let Ok(number) = time.parse::<u64>() {
+        // seconds would only be this large in the year 5000
+        return Ok(if number > 100_000_000_000 {
+            number
+        } else {
+            number * 1000
+        });
+    }
+    let date = OffsetDateTime::parse(time, &Rfc3339)
+        .with_context(|| format!("invalid timestamp {time:?}"))?;
+    u64::try_from(date.unix_timestamp_nanos() / 1_000_000)
+        .with_context(|| format!("timestamp {time:?} is before 1970"))
+}
+
+/// Apps send negative values when they do not know the speed or bearing.
+fn known(value: Option<f64>) -> Option<f64> {
+    value.filter(|value| *value >= 0.0)
+}
+
+fn validate(traccar: Traccar) -> anyhow::Result<Traccar> {
+    if traccar.id.is_empty() {
+        bail!("missing device id");
+    }
+    if traccar.id.len() > MAX_ID_LEN {
+        bail!("device id is longer than {MAX_ID_LEN} bytes");
+    }
+    let numbers = [
+        traccar.speed,
+        traccar.bearing,
+        traccar.altitude,
+        traccar.accuracy,
+        traccar.battery,
+    ];
+    if numbers
+        .into_iter()
+        .flatten()
+        .any(|number| !number.is_finite())
+    {
+        bail!("invalid number");
+    }
+    Ok(traccar)
+}
+
+fn position(lat: f64, lon: f64) -> anyhow::Result<Coordinate> {
+    Coordinate::wgs84(lat, lon).with_context(|| format!("invalid position {lat}, {lon}"))
+}
+
+/// Reads a report in the OsmAnd protocol.
+fn from_osmand(params: &HashMap<String, String>) -> anyhow::Result<Traccar> {
+    let get = |names: &[&str]| names.iter().find_map(|name| params.get(*name));
+    let number = |names: &[&str]| {
+        get(names)
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid {}: {value:?}", names[0]))
+            })
+            .transpose()
+    };
+
+    let id = get(&["id", "deviceid"]).context("missing device id")?;
+    let (lat, lon) = match get(&["location"]) {
+        Some(location) => location
+            .split_once(',')
+            .context("location should be `lat,lon`")?,
+        None => (
+            &**get(&["lat"]).context("missing lat")?,
+            &**get(&["lon"]).context("missing lon")?,
+        ),
+    };
+    let lat = lat.trim().parse().context("invalid latitude")?;
+    let lon = lon.trim().parse().context("invalid longitude")?;
+    let time = match get(&["timestamp"]) {
+        Some(time) => parse_time(time)?,
+        None => now(),
+    };
+
+    validate(Traccar {
+        id: id.clone(),
+        position: position(lat, lon)?,
+        time,
+        speed: known(number(&["speed"])?).map(|knots| knots * KNOT),
+        bearing: known(number(&["bearing", "heading"])?),
+        altitude: number(&["altitude"])?,
+        accuracy: known(number(&["accuracy"])?),
+        battery: known(number(&["batt"])?),
+    })
+}
+
+#[derive(Deserialize)]
+struct JsonReport {
+    device_id: String,
+    location: Locations,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Locations {
+    One(Location),
+    Batch(Vec<Location>),
+}
+
+#[derive(Deserialize)]
+struct Location {
+    timestamp: Option<String>,
+    coords: Coords,
+    battery: Option<Battery>,
+}
+
+#[derive(Deserialize)]
+struct Coords {
+    latitude: f64,
+    longitude: f64,
+    speed: Option<f64>,
+    heading: Option<f64>,
+    altitude: Option<f64>,
+    accuracy: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct Battery {
+    /// Between 0 and 1
+    level: Option<f64>,
+}
+
+/// Reads a JSON report, every location gets its own result so one bad location
+/// does not throw away the rest of a batch.
+fn from_json(body: &[u8]) -> anyhow::Result<Vec<anyhow::Result<Traccar>>> {
+    let report: JsonReport = serde_json::from_slice(body)?;
+    let locations = match report.location {
+        Locations::One(location) => vec![location],
+        Locations::Batch(locations) => locations,
+    };
+    let device_id = &report.device_id;
+    Ok(locations
+        .into_iter()
+        .map(|location| {
+            let time = match &location.timestamp {
+                Some(time) => parse_time(time)?,
+                None => now(),
+            };
+            let coords = location.coords;
+            validate(Traccar {
+                id: device_id.clone(),
+                position: position(coords.latitude, coords.longitude)?,
+                time,
+                speed: known(coords.speed),
+                bearing: known(coords.heading),
+                altitude: coords.altitude,
+                accuracy: known(coords.accuracy),
+                battery: known(location.battery.and_then(|battery| battery.level))
+                    .map(|level| level * 100.0),
+            })
+        })
+        .collect())
+}
+
+fn parse_report(
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> anyhow::Result<Vec<anyhow::Result<Traccar>>> {
+    // the traccar client posts with the fields in the query and an empty body
+    if let Some(query) = uri.query().filter(|query| !query.is_empty()) {
+        return Ok(vec![from_osmand(&serde_urlencoded::from_str(query)?)]);
+    }
+    let is_form = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        return Ok(vec![from_osmand(&serde_urlencoded::from_bytes(body)?)]);
+    }
+    from_json(body)
+}
+
+/// Stores the positions of a report, responds with an error when none of them is valid.
+///
+/// Without a valid token the positions are not shown, the device waits for approval instead.
+#[instrument(name = "traccar", skip_all, fields(device, approved = token.is_some()))]
+pub async fn receive(
+    live: &Live,
+    devices: &Devices,
+    metrics: &Metrics,
+    token: Option<Uuid>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let device = token.and_then(|token| devices.device(token));
+    let span = Span::current();
+    span.record("approved", device.is_some());
+    if let Some(id) = &device {
+        span.record("device", id);
+    }
+    let count = |result| metrics.traccar(device.as_deref(), result);
+
+    let reports = match parse_report(&uri, &headers, &body) {
+        Ok(reports) => reports,
+        Err(err) => {
+            warn!("rejected traccar report: {err}");
+            count("rejected");
+            return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    };
+
+    let mut last_error = None;
+    let mut stored = 0;
+    for report in reports {
+        let mut traccar = match report {
+            Ok(traccar) => traccar,
+            Err(err) => {
+                warn!("rejected traccar position: {err}");
+                last_error = Some(err);
+                continue;
+            }
+        };
+        let res = match &device {
+            // the token decides which device this is, so it can not pretend to be another
+            Some(id) => {
+                traccar.id = id.clone();
+                live.report(traccar)
+            }
+            None => {
+                span.record("device", &traccar.id);
+                devices.add_pending(&traccar)
+            }
+        };
+        if let Err(err) = res {
+            error!("error storing position: {err}");
+            count("error");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        stored += 1;
+    }
+    debug!(stored, "received traccar report");
+
+    match last_error {
+        Some(err) if stored == 0 => {
+            count("rejected");
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+        _ if device.is_none() => {
+            count("pending");
+            (
+                StatusCode::UNAUTHORIZED,
+                "unknown device, ask an admin to approve it",
+            )
+                .into_response()
+        }
+        _ => {
+            count("stored");
+            StatusCode::OK.into_response()
+        }
+    }
+}
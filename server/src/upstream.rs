@@ -0,0 +1,80 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use serde::de::DeserializeOwned;
+use sled::{Db, Tree};
+
+use crate::{config::Config, metrics::Metrics};
+
+/// How soon a poller tries again after the api failed, if its interval is longer.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Http client for the jotihunt api, shared by all the pollers.
+pub struct Upstream {
+    client: reqwest::Client,
+    base: String,
+    /// The last good response of some endpoints, to start from when the api is down
+    cache: Tree,
+    metrics: Arc<Metrics>,
+}
+
+impl Upstream {
+    pub fn new(config: &Config, db: &Db, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.upstream_timeout))
+            .user_agent(&config.user_agent)
+            .build()?;
+        Ok(Self {
+            client,
+            base: config.upstream.trim_end_matches('/').to_owned(),
+            cache: db.open_tree("upstream_cache")?,
+            metrics,
+        })
+    }
+
+    /// Full url of an endpoint on the api.
+    pub fn url(&self, endpoint: &str) -> String {
+        format!("{}/{endpoint}", self.base)
+    }
+
+    /// Fetches the body of an endpoint, and records how long that took.
+    async fn fetch(&self, endpoint: &str) -> reqwest::Result<Bytes> {
+        let start = Instant::now();
+        let res = async {
+            self.client
+                .get(self.url(endpoint))
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+        }
+        .await;
+        self.metrics
+            .upstream(endpoint, res.is_ok(), start.elapsed());
+        res
+    }
+
+    /// Fetches an endpoint and parses the json response.
+    pub async fn get<T: DeserializeOwned>(&self, endpoint: &str) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(&self.fetch(endpoint).await?)?)
+    }
+
+    /// Like [Upstream::get], but also stores the response for [Upstream::cached].
+    pub async fn get_and_cache<T: DeserializeOwned>(&self, endpoint: &str) -> anyhow::Result<T> {
+        let body = self.fetch(endpoint).await?;
+        // only responses that can be read are worth keeping
+        let value = serde_json::from_slice(&body)?;
+        self.cache.insert(endpoint, &*body)?;
+        Ok(value)
+    }
+
+    /// The last good response of the endpoint, if it was fetched with [Upstream::get_and_cache].
+    pub fn cached<T: DeserializeOwned>(&self, endpoint: &str) -> Option<T> {
+        let body = self.cache.get(endpoint).ok()??;
+        serde_json::from_slice(&body).ok()
+    }
+}
@@ -0,0 +1,475 @@
+//! Runs the whole server against a temporary database and a mock of the jotihunt api,
+//! and talks to it like the clients do.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::get, Router};
+use futures_util::{SinkExt, StreamExt};
+use jotihunt_shared::{
+    protocol::{ClientMessage, EditResult, ServerMessage, PROTOCOL_VERSION},
+    AtomicEdit, Traccar,
+};
+use serde::Deserialize;
+use server::{build_router, config::Config, AppState};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{
+    connect_async, tungstenite, tungstenite::Message, MaybeTlsStream, WebSocketStream,
+};
+
+const PASSWORD: &str = "hunter2";
+
+/// How long to wait for a message that should arrive.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Serves the recorded api responses of the fake api.
+async fn mock_upstream() -> String {
+    let router = Router::new().route(
+        "/api/2.0/{endpoint}",
+        get(|Path(endpoint): Path<String>| async move {
+            let json = match &*endpoint {
+                "articles" => include_str!("../fake_api/articles.json"),
+                "areas" => include_str!("../fake_api/areas.json"),
+                "subscriptions" => include_str!("../fake_api/subscriptions.json"),
+                _ => return StatusCode::NOT_FOUND.into_response(),
+            };
+            ([("content-type", "application/json")], json).into_response()
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    format!("http://{addr}/api/2.0")
+}
+
+struct TestServer {
+    addr: String,
+    /// Session key of the admin
+    key: String,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("jotihunt-test-{}-{count}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("password"), PASSWORD).unwrap();
+
+        let config = Config {
+            db: dir.join("db"),
+            password_file: dir.join("password"),
+            upstream: mock_upstream().await,
+            ..Config::default()
+        };
+        let state = AppState::open(config).unwrap();
+        state.start();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, build_router(state)).await });
+
+        let mut server = Self {
+            addr,
+            key: String::new(),
+            dir,
+        };
+        server.key = server.login("admin", PASSWORD).await;
+        server
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}/{path}", self.addr)
+    }
+
+    fn ws_url(&self, key: &str, path: &str) -> String {
+        format!("ws://{}/{key}/{path}", self.addr)
+    }
+
+    /// A new session key of the user.
+    async fn login(&self, name: &str, password: &str) -> String {
+        reqwest::Client::new()
+            .get(self.url("secret"))
+            .basic_auth(name, Some(password))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// Creates or changes a user, as the admin.
+    async fn set_user(&self, name: &str, password: &str, role: &str) {
+        reqwest::Client::new()
+            .post(self.url(&format!("{}/admin/users", self.key)))
+            .json(&serde_json::json!({ "name": name, "password": password, "role": role }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+
+    /// A client of a synced tree as the admin, after the handshake.
+    async fn sync(&self, tree: &str) -> SyncClient {
+        self.sync_as(&self.key, tree).await
+    }
+
+    async fn sync_as(&self, key: &str, tree: &str) -> SyncClient {
+        let (ws, _) = connect_async(self.ws_url(key, tree)).await.unwrap();
+        let mut client = SyncClient { ws };
+        client
+            .send(ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+            })
+            .await;
+        assert_eq!(
+            client.recv().await,
+            ServerMessage::Hello {
+                version: PROTOCOL_VERSION
+            }
+        );
+        client.send(ClientMessage::Sync { since: None }).await;
+        client
+    }
+
+    async fn live(&self) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+        connect_async(self.ws_url(&self.key, "live"))
+            .await
+            .unwrap()
+            .0
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct SyncClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl SyncClient {
+    async fn send(&mut self, msg: ClientMessage) {
+        let bin = postcard::to_stdvec(&msg).unwrap();
+        self.ws.send(Message::Binary(bin.into())).await.unwrap();
+    }
+
+    /// The next message that is not a ping.
+    async fn recv(&mut self) -> ServerMessage {
+        loop {
+            let msg = timeout(PATIENCE, self.ws.next())
+                .await
+                .expect("the server should send a message")
+                .expect("the connection should stay open")
+                .unwrap();
+            if let Message::Binary(bin) = msg {
+                match postcard::from_bytes(&bin).unwrap() {
+                    ServerMessage::Ping => continue,
+                    msg => return msg,
+                }
+            }
+        }
+    }
+
+    async fn snapshot(&mut self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        match self.recv().await {
+            ServerMessage::Snapshot { entries, .. } => entries.into_iter().collect(),
+            msg => panic!("expected a snapshot, got {msg:?}"),
+        }
+    }
+
+    /// Sends an edit and returns the result, an applied edit also waits for its own change.
+    async fn edit(&mut self, id: u32, key: &[u8], old: &[u8], new: &[u8]) -> EditResult {
+        let edit = AtomicEdit {
+            key: key.to_vec(),
+            old: old.to_vec(),
+            new: new.to_vec(),
+        };
+        self.send(ClientMessage::Edit { id, edit }).await;
+        // the ack and the change are sent independently, in any order
+        let (mut result, mut changed) = (None, false);
+        loop {
+            match self.recv().await {
+                ServerMessage::Ack {
+                    id: acked,
+                    result: r,
+                } if acked == id => result = Some(r),
+                ServerMessage::Insert { key: k, .. } | ServerMessage::Remove { key: k, .. }
+                    if k == key =>
+                {
+                    changed = true
+                }
+                msg => panic!("expected an ack, got {msg:?}"),
+            }
+            match result {
+                Some(EditResult::Applied) if !changed => {}
+                Some(result) => return result,
+                None => {}
+            }
+        }
+    }
+}
+
+async fn next_change(client: &mut SyncClient) -> (Vec<u8>, Option<Vec<u8>>) {
+    match client.recv().await {
+        ServerMessage::Insert { key, value, .. } => (key, Some(value)),
+        ServerMessage::Remove { key, .. } => (key, None),
+        msg => panic!("expected a change, got {msg:?}"),
+    }
+}
+
+#[tokio::test]
+async fn clients_get_a_snapshot_and_then_the_changes() {
+    let server = TestServer::start().await;
+    let mut first = server.sync("locations").await;
+    let mut second = server.sync("locations").await;
+    assert!(first.snapshot().await.is_empty());
+    assert!(second.snapshot().await.is_empty());
+
+    assert_eq!(
+        first.edit(1, b"fox", b"", b"here").await,
+        EditResult::Applied
+    );
+    assert_eq!(
+        next_change(&mut second).await,
+        (b"fox".to_vec(), Some(b"here".to_vec()))
+    );
+
+    let mut late = server.sync("locations").await;
+    let snapshot = late.snapshot().await;
+    assert_eq!(snapshot.get(&b"fox"[..]), Some(&b"here".to_vec()));
+}
+
+#[tokio::test]
+async fn edits_only_apply_to_the_value_they_expect() {
+    let server = TestServer::start().await;
+    let mut first = server.sync("locations").await;
+    let mut second = server.sync("locations").await;
+    first.snapshot().await;
+    second.snapshot().await;
+
+    assert_eq!(
+        first.edit(1, b"fox", b"", b"one").await,
+        EditResult::Applied
+    );
+    next_change(&mut second).await;
+
+    // the second client did not know about the first edit
+    assert_eq!(
+        second.edit(1, b"fox", b"", b"two").await,
+        EditResult::Rejected {
+            current: b"one".to_vec()
+        }
+    );
+    assert_eq!(
+        second.edit(2, b"fox", b"one", b"two").await,
+        EditResult::Applied
+    );
+    assert_eq!(
+        next_change(&mut first).await,
+        (b"fox".to_vec(), Some(b"two".to_vec()))
+    );
+    assert_eq!(
+        first.edit(2, b"fox", b"one", b"three").await,
+        EditResult::Rejected {
+            current: b"two".to_vec()
+        }
+    );
+}
+
+#[tokio::test]
+async fn an_empty_new_value_removes_the_key() {
+    let server = TestServer::start().await;
+    let mut first = server.sync("locations").await;
+    let mut second = server.sync("locations").await;
+    first.snapshot().await;
+    second.snapshot().await;
+
+    assert_eq!(
+        first.edit(1, b"fox", b"", b"here").await,
+        EditResult::Applied
+    );
+    next_change(&mut second).await;
+    assert_eq!(
+        first.edit(2, b"fox", b"here", b"").await,
+        EditResult::Applied
+    );
+    assert_eq!(next_change(&mut second).await, (b"fox".to_vec(), None));
+
+    let mut late = server.sync("locations").await;
+    assert!(late.snapshot().await.is_empty());
+}
+
+#[tokio::test]
+async fn status_and_articles_come_from_the_api() {
+    #[derive(Deserialize)]
+    struct Data {
+        data: Vec<serde_json::Value>,
+    }
+    let count = |json: &str| serde_json::from_str::<Data>(json).unwrap().data.len();
+
+    let server = TestServer::start().await;
+    let mut status = server.sync("status").await;
+    let areas = count(include_str!("../fake_api/areas.json"));
+    assert_eq!(synced_len(&mut status, areas).await, areas);
+
+    let mut articles = server.sync("articles").await;
+    let expected = count(include_str!("../fake_api/articles.json"));
+    assert_eq!(synced_len(&mut articles, expected).await, expected);
+}
+
+/// The size of the tree once it has `expected` entries, the api is polled in the background.
+async fn synced_len(client: &mut SyncClient, expected: usize) -> usize {
+    let mut entries = client.snapshot().await;
+    while entries.len() < expected {
+        match next_change(client).await {
+            (key, Some(value)) => entries.insert(key, value),
+            (key, None) => entries.remove(&key),
+        };
+    }
+    entries.len()
+}
+
+#[tokio::test]
+async fn unknown_keys_are_refused() {
+    let server = TestServer::start().await;
+    let addr = &server.addr;
+    for key in ["00000000-0000-0000-0000-000000000000", "not-a-key"] {
+        for tree in ["locations", "status", "articles", "live"] {
+            match connect_async(format!("ws://{addr}/{key}/{tree}")).await {
+                Err(tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED)
+                }
+                Err(err) => panic!("expected 401 for {tree}, got {err}"),
+                Ok(_) => panic!("connected to {tree} with key {key}"),
+            }
+        }
+    }
+    let response = reqwest::get(server.url("not-a-key/session")).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn viewers_can_not_edit_and_a_new_password_ends_their_session() {
+    let server = TestServer::start().await;
+    server.set_user("watcher", "binoculars", "viewer").await;
+    let key = server.login("watcher", "binoculars").await;
+
+    let mut viewer = server.sync_as(&key, "locations").await;
+    viewer.snapshot().await;
+    assert_eq!(
+        viewer.edit(1, b"fox", b"", b"here").await,
+        EditResult::Forbidden
+    );
+
+    server.set_user("watcher", "telescope", "viewer").await;
+    let response = reqwest::get(server.url(&format!("{key}/session")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn open_connections_stop_editing_when_the_session_ends() {
+    let server = TestServer::start().await;
+    server.set_user("hunter", "compass", "editor").await;
+    let key = server.login("hunter", "compass").await;
+
+    let mut editor = server.sync_as(&key, "locations").await;
+    editor.snapshot().await;
+    assert_eq!(
+        editor.edit(1, b"fox", b"", b"here").await,
+        EditResult::Applied
+    );
+
+    let response = reqwest::Client::new()
+        .delete(server.url(&format!("{key}/session")))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    assert_eq!(
+        editor.edit(2, b"fox", b"here", b"there").await,
+        EditResult::Forbidden
+    );
+    let msg = timeout(PATIENCE, editor.ws.next())
+        .await
+        .expect("the server should close the connection");
+    match msg {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(
+                frame.code,
+                tungstenite::protocol::frame::coding::CloseCode::Policy
+            )
+        }
+        msg => panic!("expected a close frame, got {msg:?}"),
+    }
+}
+
+#[tokio::test]
+async fn traccar_reports_reach_every_live_client() {
+    #[derive(Deserialize)]
+    struct DeviceInfo {
+        token: String,
+    }
+
+    let server = TestServer::start().await;
+    let http = reqwest::Client::new();
+    let device: DeviceInfo = http
+        .post(server.url(&format!("{}/admin/devices", server.key)))
+        .json(&serde_json::json!({ "id": "phone" }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let mut clients = vec![server.live().await, server.live().await];
+
+    // an unapproved device is not shown
+    let query = "id=stranger&lat=52.1&lon=5.8&timestamp=1700000000";
+    let response = http
+        .post(server.url(&format!("traccar?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // the token decides the device, whatever id the report has
+    let query = "id=whatever&lat=52.1&lon=5.8&timestamp=1700000000";
+    let response = http
+        .post(server.url(&format!("traccar/{}?{query}", device.token)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    for client in &mut clients {
+        let msg = timeout(PATIENCE, client.next())
+            .await
+            .expect("the report should be sent to every client")
+            .unwrap()
+            .unwrap();
+        let Message::Binary(bin) = msg else {
+            panic!("expected a binary message, got {msg:?}");
+        };
+        let traccar: Traccar = postcard::from_bytes(&bin).unwrap();
+        assert_eq!(traccar.id, "phone");
+        assert_eq!(traccar.time, 1_700_000_000_000);
+    }
+}
@@ -0,0 +1,337 @@
+//! Positions on the map, in the notations the hunters use.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Rijksdriehoek coordinates in meters that lie in or near the Netherlands.
+const RD_X: std::ops::RangeInclusive<u32> = 0..=300_000;
+const RD_Y: std::ops::RangeInclusive<u32> = 300_000..=625_000;
+
+/// A position as entered by a hunter or reported by a device.
+///
+/// Construct it with [Coordinate::from_input], [Coordinate::parse] or [Coordinate::wgs84],
+/// those make sure the numbers are valid. Deserializing checks them too.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "Unchecked")]
+pub enum Coordinate {
+    /// Rijksdriehoek in hectometers, the 4 digit notation of the jotihunt
+    RdShort { x: u16, y: u16 },
+    /// Rijksdriehoek in meters
+    Rd { x: u32, y: u32 },
+    /// Degrees
+    Wgs84 { lat: f64, lon: f64 },
+    /// Input that is not a valid coordinate (yet), kept so nothing that was typed is lost
+    Raw { x: String, y: String },
+}
+
+/// The same layout as [Coordinate], for checking what is deserialized.
+#[derive(Deserialize)]
+#[serde(rename = "Coordinate")]
+enum Unchecked {
+    RdShort { x: u16, y: u16 },
+    Rd { x: u32, y: u32 },
+    Wgs84 { lat: f64, lon: f64 },
+    Raw { x: String, y: String },
+}
+
+impl TryFrom<Unchecked> for Coordinate {
+    type Error = CoordinateError;
+
+    fn try_from(unchecked: Unchecked) -> Result<Self, CoordinateError> {
+        Ok(match unchecked {
+            Unchecked::RdShort { x, y } => {
+                rd(u32::from(x) * 100, u32::from(y) * 100)?;
+                Coordinate::RdShort { x, y }
+            }
+            Unchecked::Rd { x, y } => {
+                let (x, y) = rd(x, y)?;
+                Coordinate::Rd { x, y }
+            }
+            Unchecked::Wgs84 { lat, lon } => Coordinate::wgs84(lat, lon)?,
+            Unchecked::Raw { x, y } => Coordinate::Raw { x, y },
+        })
+    }
+}
+
+/// Why input could not be read as a [Coordinate].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateError {
+    Empty,
+    /// Not a notation we know
+    Unrecognized,
+    /// A known notation, but not a place the jotihunt could be
+    OutOfRange,
+}
+
+impl fmt::Display for CoordinateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CoordinateError::Empty => "no coordinate",
+            CoordinateError::Unrecognized => "not a coordinate",
+            CoordinateError::OutOfRange => "coordinate out of range",
+        })
+    }
+}
+
+impl std::error::Error for CoordinateError {}
+
+fn digits(input: &str, count: std::ops::RangeInclusive<usize>) -> Option<u32> {
+    if !count.contains(&input.len()) || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    input.parse().ok()
+}
+
+fn rd(x: u32, y: u32) -> Result<(u32, u32), CoordinateError> {
+    if RD_X.contains(&x) && RD_Y.contains(&y) {
+        Ok((x, y))
+    } else {
+        Err(CoordinateError::OutOfRange)
+    }
+}
+
+impl Coordinate {
+    /// Checks that the degrees are a position on earth, which also rules out NaN.
+    pub fn wgs84(lat: f64, lon: f64) -> Result<Self, CoordinateError> {
+        if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+            // -0.0 is the same place as 0.0, but the bits differ
+            Ok(Coordinate::Wgs84 {
+                lat: lat + 0.0,
+                lon: lon + 0.0,
+            })
+        } else {
+            Err(CoordinateError::OutOfRange)
+        }
+    }
+
+    /// Reads the two fields of a coordinate: x and y for rijksdriehoek, or latitude and longitude.
+    ///
+    /// Four digits are the hectometers of the jotihunt, five or six digits are meters
+    /// and numbers with a decimal point (or comma) are degrees.
+    pub fn parse(x: &str, y: &str) -> Result<Self, CoordinateError> {
+        let (x, y) = (x.trim(), y.trim());
+        if x.is_empty() && y.is_empty() {
+            return Err(CoordinateError::Empty);
+        }
+        if let (Some(short_x), Some(short_y)) = (digits(x, 4..=4), digits(y, 4..=4)) {
+            rd(short_x * 100, short_y * 100)?;
+            return Ok(Coordinate::RdShort {
+                x: short_x as u16,
+                y: short_y as u16,
+            });
+        }
+        if let (Some(x), Some(y)) = (digits(x, 5..=6), digits(y, 5..=6)) {
+            let (x, y) = rd(x, y)?;
+            return Ok(Coordinate::Rd { x, y });
+        }
+        let degrees = |input: &str| input.replace(',', ".").parse::<f64>().ok();
+        match (degrees(x), degrees(y)) {
+            (Some(lat), Some(lon)) => Coordinate::wgs84(lat, lon),
+            _ => Err(CoordinateError::Unrecognized),
+        }
+    }
+
+    /// Like [Coordinate::parse], but keeps invalid input as [Coordinate::Raw].
+    pub fn from_input(x: &str, y: &str) -> Self {
+        Coordinate::parse(x, y).unwrap_or_else(|_| Coordinate::Raw {
+            x: x.trim().to_owned(),
+            y: y.trim().to_owned(),
+        })
+    }
+
+    /// The two fields as they would be typed, [Coordinate::from_input] reads them back.
+    pub fn fields(&self) -> (String, String) {
+        match self {
+            Coordinate::RdShort { x, y } => (format!("{x:04}"), format!("{y:04}")),
+            Coordinate::Rd { x, y } => (x.to_string(), y.to_string()),
+            Coordinate::Wgs84 { lat, lon } => (lat.to_string(), lon.to_string()),
+            Coordinate::Raw { x, y } => (x.clone(), y.clone()),
+        }
+    }
+
+    /// Nothing was entered.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Coordinate::Raw { x, y } if x.is_empty() && y.is_empty())
+    }
+
+    /// The rijksdriehoek position in meters, if the coordinate is in rijksdriehoek.
+    pub fn rd_meters(&self) -> Option<(f64, f64)> {
+        match *self {
+            Coordinate::RdShort { x, y } => Some((x as f64 * 100.0, y as f64 * 100.0)),
+            Coordinate::Rd { x, y } => Some((x as f64, y as f64)),
+            _ => None,
+        }
+    }
+
+    /// The position as `(latitude, longitude)`, or nothing for [Coordinate::Raw].
+    pub fn to_wgs84(&self) -> Option<(f64, f64)> {
+        if let Some((x, y)) = self.rd_meters() {
+            return Some(crate::rd::to_wgs84(x, y));
+        }
+        match *self {
+            Coordinate::Wgs84 { lat, lon } => Some((lat, lon)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Coordinate {
+    fn default() -> Self {
+        Coordinate::Raw {
+            x: String::new(),
+            y: String::new(),
+        }
+    }
+}
+
+impl Coordinate {
+    /// The position of the variant, which orders the notations.
+    fn variant(&self) -> u8 {
+        match self {
+            Coordinate::RdShort { .. } => 0,
+            Coordinate::Rd { .. } => 1,
+            Coordinate::Wgs84 { .. } => 2,
+            Coordinate::Raw { .. } => 3,
+        }
+    }
+}
+
+// degrees are compared by their bits, like they are hashed, so `Eq` and `Hash` agree
+impl Ord for Coordinate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Coordinate::RdShort { x, y }, Coordinate::RdShort { x: x2, y: y2 }) => {
+                (x, y).cmp(&(x2, y2))
+            }
+            (Coordinate::Rd { x, y }, Coordinate::Rd { x: x2, y: y2 }) => (x, y).cmp(&(x2, y2)),
+            (
+                Coordinate::Wgs84 { lat, lon },
+                Coordinate::Wgs84 {
+                    lat: lat2,
+                    lon: lon2,
+                },
+            ) => lat.total_cmp(lat2).then(lon.total_cmp(lon2)),
+            (Coordinate::Raw { x, y }, Coordinate::Raw { x: x2, y: y2 }) => (x, y).cmp(&(x2, y2)),
+            _ => self.variant().cmp(&other.variant()),
+        }
+    }
+}
+
+impl PartialOrd for Coordinate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Coordinate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Coordinate {}
+
+impl Hash for Coordinate {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Coordinate::RdShort { x, y } => (x, y).hash(state),
+            Coordinate::Rd { x, y } => (x, y).hash(state),
+            Coordinate::Wgs84 { lat, lon } => (lat.to_bits(), lon.to_bits()).hash(state),
+            Coordinate::Raw { x, y } => (x, y).hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Coordinate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (x, y) = self.fields();
+        write!(f, "{x}, {y}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_notations() {
+        assert_eq!(
+            Coordinate::parse("1873", " 4426"),
+            Ok(Coordinate::RdShort { x: 1873, y: 4426 })
+        );
+        assert_eq!(
+            Coordinate::parse("187312", "442604"),
+            Ok(Coordinate::Rd {
+                x: 187312,
+                y: 442604
+            })
+        );
+        assert_eq!(
+            Coordinate::parse("51,95", "5.87"),
+            Ok(Coordinate::Wgs84 {
+                lat: 51.95,
+                lon: 5.87
+            })
+        );
+        assert_eq!(
+            Coordinate::parse("1873", "9999"),
+            Err(CoordinateError::OutOfRange)
+        );
+        assert_eq!(
+            Coordinate::parse("18x3", "4426"),
+            Err(CoordinateError::Unrecognized)
+        );
+        assert!(Coordinate::from_input("", " ").is_empty());
+        assert_eq!(
+            Coordinate::parse("-0.0", "0"),
+            Coordinate::parse("0.0", "0")
+        );
+    }
+
+    #[test]
+    fn deserializing_checks_the_range() {
+        for valid in [
+            Coordinate::wgs84(51.95, 5.87).unwrap(),
+            Coordinate::from_input("187312", "442604"),
+            Coordinate::from_input("0950", "4426"),
+        ] {
+            let bin = postcard::to_allocvec(&valid).unwrap();
+            assert_eq!(postcard::from_bytes::<Coordinate>(&bin), Ok(valid));
+        }
+
+        let nan = Coordinate::Wgs84 {
+            lat: f64::NAN,
+            lon: 5.87,
+        };
+        let bin = postcard::to_allocvec(&nan).unwrap();
+        assert!(postcard::from_bytes::<Coordinate>(&bin).is_err());
+
+        for outside in [
+            Coordinate::Rd { x: 187_312, y: 0 },
+            Coordinate::RdShort { x: 9999, y: 4426 },
+        ] {
+            let bin = postcard::to_allocvec(&outside).unwrap();
+            assert!(postcard::from_bytes::<Coordinate>(&bin).is_err());
+        }
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        for (x, y) in [
+            ("0950", "4426"),
+            ("187312", "442604"),
+            ("51.95", "5.87"),
+            ("18", ""),
+        ] {
+            let coordinate = Coordinate::from_input(x, y);
+            let (x, y) = coordinate.fields();
+            assert_eq!(Coordinate::from_input(&x, &y), coordinate);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::coordinate::Coordinate;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FoxKey {
     pub day: String,
@@ -9,8 +11,7 @@ pub struct FoxKey {
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Fox {
-    pub latitude: String,
-    pub longitude: String,
+    pub coordinate: Coordinate,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -31,3 +32,27 @@ pub struct SavedArticle {
     pub r#type: String,
     pub content: String,
 }
+
+/// Whether the coordinate of a [Fox] lies in the area of its `fox_name`, checked by the server.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub enum AreaCheck {
+    Inside,
+    /// The area the coordinate lies in instead, if any
+    Outside {
+        area: Option<String>,
+    },
+}
+
+/// A participating group, keyed by its name.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Participant {
+    /// Always [Coordinate::Wgs84]
+    pub position: Coordinate,
+    pub accommodation: Option<String>,
+    /// Street and house number
+    pub address: Option<String>,
+    pub postcode: Option<String>,
+    pub city: Option<String>,
+    /// The area the group is in
+    pub area: Option<String>,
+}
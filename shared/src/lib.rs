@@ -1,23 +1,119 @@
+pub mod coordinate;
 pub mod domain;
+pub mod protocol;
+pub mod rd;
 
+use coordinate::Coordinate;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AtomicEdit {
     pub key: Vec<u8>,
     pub old: Vec<u8>,
     pub new: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Broadcast {
+/// A position reported by the traccar client of a hunter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Traccar {
+    pub id: String,
+    /// Always [Coordinate::Wgs84]
+    pub position: Coordinate,
+    /// Milliseconds since the unix epoch, when the device measured the position
+    pub time: u64,
+    /// Meters per second
+    pub speed: Option<f64>,
+    /// Degrees clockwise from north
+    pub bearing: Option<f64>,
+    /// Meters above sea level
+    pub altitude: Option<f64>,
+    /// Radius in meters
+    pub accuracy: Option<f64>,
+    /// Percentage of the battery that is left
+    pub battery: Option<f64>,
+}
+
+/// How a traccar device is shown on the map, stored in the `registry` tree under its id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Device {
+    /// Name of the hunter carrying the device
+    pub name: String,
+    /// Car or team the hunter is in
+    pub team: String,
+    /// One of [MARKER_COLOURS], the human icon is used when empty
+    pub colour: String,
+}
+
+/// Colours the map has markers for.
+pub const MARKER_COLOURS: [&str; 9] = [
+    "blue", "gold", "red", "green", "orange", "yellow", "violet", "grey", "black",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can only watch the map
+    Viewer,
+    /// Can also enter fox locations
+    Editor,
+    /// Can also manage users and sessions
+    Admin,
+}
+
+/// Key of an entry in the audit log, ordered by time.
+///
+/// Serialized as the big endian time and id, so the bytes sort in the same order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(from = "[u8; 16]", into = "[u8; 16]")]
+pub struct AuditKey {
+    /// Milliseconds since the unix epoch
+    pub time: u64,
+    pub id: u64,
+}
+
+impl From<AuditKey> for [u8; 16] {
+    fn from(key: AuditKey) -> Self {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&key.time.to_be_bytes());
+        bytes[8..].copy_from_slice(&key.id.to_be_bytes());
+        bytes
+    }
+}
+
+impl From<[u8; 16]> for AuditKey {
+    fn from(bytes: [u8; 16]) -> Self {
+        let (time, id) = bytes.split_at(8);
+        AuditKey {
+            time: u64::from_be_bytes(time.try_into().unwrap()),
+            id: u64::from_be_bytes(id.try_into().unwrap()),
+        }
+    }
+}
+
+/// An [AtomicEdit] received by the server, and whether it was applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AuditEntry {
+    pub user: String,
     pub key: Vec<u8>,
-    pub value: Vec<u8>,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    pub applied: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Traccar {
-    pub id: String,
-    pub lat: String,
-    pub lon: String,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_keys_sort_by_time() {
+        let early = AuditKey { time: 255, id: 300 };
+        let late = AuditKey { time: 256, id: 1 };
+        let early = postcard::to_allocvec(&early).unwrap();
+        let late = postcard::to_allocvec(&late).unwrap();
+        assert!(early < late);
+        assert_eq!(
+            postcard::from_bytes::<AuditKey>(&late).unwrap(),
+            AuditKey { time: 256, id: 1 }
+        );
+    }
 }
@@ -0,0 +1,100 @@
+//! Messages sent over the websockets that sync a tree.
+//!
+//! Every message is a postcard encoded [ClientMessage] or [ServerMessage] in a binary frame.
+//! The client starts with [ClientMessage::Hello] and [ClientMessage::Sync],
+//! the server answers with [ServerMessage::Hello] or with [ServerMessage::Error] if the versions do not match.
+//!
+//! After the handshake the server sends a [ServerMessage::Snapshot] if the client has no data yet,
+//! followed by every change to the tree. A client that reconnects only receives the changes it missed.
+//!
+//! The `Hello` variants must stay the first variant with the same fields,
+//! so that clients and servers of any version can read each others handshake.
+
+use serde::{Deserialize, Serialize};
+
+use crate::AtomicEdit;
+
+/// Increase this when changing any of the messages, or the encoding of the synced values.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+    },
+    /// Sent right after the hello, with the last revision the client has seen.
+    Sync {
+        since: Option<Revision>,
+    },
+    /// The server responds with [ServerMessage::Ack] with the same `id`.
+    Edit {
+        id: u32,
+        edit: AtomicEdit,
+    },
+    Ping,
+    Pong,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    Hello {
+        version: u32,
+    },
+    /// All keys and values in the tree, replaces everything the client had.
+    Snapshot {
+        revision: Revision,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    Insert {
+        revision: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        revision: u64,
+        key: Vec<u8>,
+    },
+    Ack {
+        id: u32,
+        result: EditResult,
+    },
+    /// Something went wrong, the message can be shown to the user.
+    Error {
+        message: String,
+    },
+    Ping,
+    Pong,
+}
+
+/// Position in the change log of a tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Revision {
+    /// Changes when the log on the server is replaced
+    pub log: u64,
+    /// Number of changes in the log
+    pub revision: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EditResult {
+    Applied,
+    /// The old value did not match, `current` is the value on the server (empty if there is none).
+    Rejected {
+        current: Vec<u8>,
+    },
+    /// The user is not allowed to edit this tree, trying again will not help.
+    Forbidden,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_is_stable() {
+        let client = postcard::to_stdvec(&ClientMessage::Hello { version: 1 }).unwrap();
+        let server = postcard::to_stdvec(&ServerMessage::Hello { version: 1 }).unwrap();
+        assert_eq!(client, [0, 1]);
+        assert_eq!(server, [0, 1]);
+    }
+}